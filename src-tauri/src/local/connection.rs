@@ -4,8 +4,11 @@ use std::io::{Read, Write};
 use std::sync::Arc;
 use tokio::sync::{mpsc, watch, Mutex};
 use std::env;
+use std::path::Path;
 use tauri::Emitter;
 
+use crate::metrics::{self, SessionMetrics};
+
 #[derive(Clone)]
 pub struct LocalConnection {
     session_id: String,
@@ -15,6 +18,7 @@ pub struct LocalConnection {
     writer: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
     shutdown_tx: Arc<Mutex<Option<watch::Sender<bool>>>>,
     input_tx: Arc<Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>,
+    metrics: Arc<SessionMetrics>,
 }
 
 impl LocalConnection {
@@ -27,6 +31,7 @@ impl LocalConnection {
             writer: Arc::new(Mutex::new(None)),
             shutdown_tx: Arc::new(Mutex::new(None)),
             input_tx: Arc::new(Mutex::new(None)),
+            metrics: Arc::new(SessionMetrics::default()),
         }
     }
 
@@ -34,6 +39,7 @@ impl LocalConnection {
         &mut self,
         shell: Option<String>,
         cwd: Option<String>,
+        agent_socket: Option<&Path>,
         app_handle: tauri::AppHandle,
     ) -> Result<()> {
         // Get shell to use
@@ -71,6 +77,11 @@ impl LocalConnection {
         cmd.env("LANG", "en_US.UTF-8");
         cmd.env("LC_ALL", "en_US.UTF-8");
 
+        // Point ssh, git and friends at the app's built-in agent
+        if let Some(socket) = agent_socket {
+            cmd.env("SSH_AUTH_SOCK", socket);
+        }
+
         // Spawn child process
         let child = pty_pair
             .slave
@@ -104,6 +115,15 @@ impl LocalConnection {
         let mut reader_clone = self.reader.lock().await.take().ok_or_else(|| anyhow!("Failed to take reader"))?;
         let child_clone = self.child.clone();
         let shutdown_rx_clone = shutdown_rx.clone();
+        let writer_metrics = Arc::clone(&self.metrics);
+        let reader_metrics = Arc::clone(&self.metrics);
+
+        metrics::spawn_reporter(
+            app_handle.clone(),
+            self.session_id.clone(),
+            Arc::clone(&self.metrics),
+            shutdown_rx,
+        );
 
         // Writer task
         tokio::spawn(async move {
@@ -111,6 +131,7 @@ impl LocalConnection {
                 if let Err(_e) = writer_clone.write_all(&data) {
                     break;
                 }
+                writer_metrics.record_write(data.len());
                 if let Err(_e) = writer_clone.flush() {
                     break;
                 }
@@ -132,6 +153,7 @@ impl LocalConnection {
                         break;
                     },
                     Ok(n) => {
+                        reader_metrics.record_read(n);
                         let data = String::from_utf8_lossy(&buffer[..n]).to_string();
                         let _ = app_handle_clone.emit(&format!("terminal-output:{}", session_id_clone), data);
                     },
@@ -184,6 +206,10 @@ impl LocalConnection {
         }
     }
 
+    pub fn metrics(&self) -> Arc<SessionMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
     pub async fn disconnect(&mut self) -> Result<()> {
         // Signal shutdown
         if let Some(ref tx) = *self.shutdown_tx.lock().await {
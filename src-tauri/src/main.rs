@@ -3,21 +3,47 @@
 
 mod ssh;
 mod local;
+mod metrics;
 
 use ssh::{
-    connection::SshConnection,
-    keygen::{generate_ed25519_keypair, get_key_type},
-    sftp::{list_directory, download_file, upload_file},
+    agent::{AddKeyOptions, AgentIdentity, AgentStatus, SshAgent},
+    auth::{expand_tilde, AuthOptions, KeyUnlocker},
+    authorized_keys::{self, AuthorizedKey, AuthorizedKeysEdit, InstallKeyResult},
+    connection::{SessionMetadata, SshConnection},
+    credentials,
+    diagnostics::{prune_failed_traces, ConnectionTrace},
+    key_inventory::{self, InventoryEntry},
+    keygen::{self, get_key_type, ConvertFormat, KeyGenOptions, KeyInfo},
+    known_hosts::{self, HostKeyDecision, KnownHost, KnownHostsEdit, ScannedHostKey},
+    prompt::PromptBroker,
+    remote_edit::{EditSessionInfo, EditState, RemoteEditor},
+    sftp::{
+        self, list_directory, download_directory, download_file, upload_directory, upload_file,
+        TreeOptions,
+    },
+    sync::{self, SyncPlan, SyncProfile},
+    totp::{self, TotpCode, TotpConfig},
+    transfer::{ConflictAnswer, TransferDirection, TransferManager, TransferOptions},
+    transfer_queue::{NewJob, QueueLimits, QueueSnapshot, QueuedJob, TransferQueue},
 };
 use local::connection::LocalConnection;
+use metrics::MetricsSnapshot;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tauri::State;
+use tauri::{Manager, State};
 use tokio::sync::Mutex;
 
 pub struct AppState {
     connections: Arc<Mutex<HashMap<String, SshConnection>>>,
     local_connections: Arc<Mutex<HashMap<String, LocalConnection>>>,
+    prompts: PromptBroker,
+    key_unlocker: KeyUnlocker,
+    connection_traces: Arc<Mutex<HashMap<String, ConnectionTrace>>>,
+    agent: SshAgent,
+    transfers: TransferManager,
+    remote_editor: RemoteEditor,
+    transfer_queue: TransferQueue,
 }
 
 #[tauri::command]
@@ -28,15 +54,41 @@ async fn ssh_connect(
     username: String,
     private_key_path: Option<String>,
     passphrase: Option<String>,
+    auth: Option<AuthOptions>,
     app_handle: tauri::AppHandle,
     state: State<'_, AppState>,
 ) -> Result<String, String> {
     let mut connection = SshConnection::new(session_id.clone());
 
-    connection
-        .connect(host, port, username, private_key_path, passphrase, app_handle)
-        .await
-        .map_err(|e| e.to_string())?;
+    // The legacy key/passphrase arguments take precedence over the profile's list
+    let mut auth = auth.unwrap_or_default();
+    if let Some(path) = private_key_path {
+        auth.identity_files.insert(0, path);
+    }
+    if passphrase.is_some() {
+        auth.passphrase = passphrase;
+    }
+
+    let result = connection
+        .connect(
+            host,
+            port,
+            username,
+            auth,
+            state.key_unlocker.clone(),
+            state.prompts.clone(),
+            app_handle,
+        )
+        .await;
+
+    // Keep the trace around even when connecting failed, for ssh_connection_info.
+    // Only the latest failed ones are kept, since retries use new session ids.
+    if let Some(trace) = connection.trace().await {
+        let mut traces = state.connection_traces.lock().await;
+        traces.insert(session_id.clone(), trace);
+        prune_failed_traces(&mut traces);
+    }
+    result.map_err(|e| e.to_string())?;
 
     let mut connections = state.connections.lock().await;
     connections.insert(session_id.clone(), connection);
@@ -44,6 +96,289 @@ async fn ssh_connect(
     Ok(session_id)
 }
 
+#[tauri::command]
+async fn ssh_session_metadata(
+    session_id: String,
+    state: State<'_, AppState>,
+) -> Result<SessionMetadata, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+
+    if let Some(connection) = connection {
+        Ok(connection.metadata().await)
+    } else {
+        Err("Connection not found".to_string())
+    }
+}
+
+#[tauri::command]
+async fn ssh_install_public_key(
+    session_id: String,
+    key_path: String,
+    passphrase: Option<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<InstallKeyResult, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    authorized_keys::install_key(
+        &connection,
+        &expand_tilde(&key_path),
+        passphrase,
+        &state.key_unlocker,
+        &app_handle,
+        &session_id,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn authorized_keys_list(
+    session_id: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<AuthorizedKey>, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    authorized_keys::list(&connection)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn authorized_keys_add(
+    session_id: String,
+    public_key: String,
+    options: Option<Vec<String>>,
+    state: State<'_, AppState>,
+) -> Result<AuthorizedKeysEdit, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    authorized_keys::add(&connection, &public_key, &options.unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn authorized_keys_set_enabled(
+    session_id: String,
+    line: usize,
+    fingerprint: String,
+    enabled: bool,
+    state: State<'_, AppState>,
+) -> Result<AuthorizedKeysEdit, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    authorized_keys::set_enabled(&connection, line, &fingerprint, enabled)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn authorized_keys_remove(
+    session_id: String,
+    line: usize,
+    fingerprint: String,
+    state: State<'_, AppState>,
+) -> Result<AuthorizedKeysEdit, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    authorized_keys::remove(&connection, line, &fingerprint)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// `known_hosts` file to operate on; defaults to the one connections verify against.
+fn known_hosts_path(path: Option<String>) -> PathBuf {
+    path.map(|p| expand_tilde(&p))
+        .unwrap_or_else(known_hosts::default_path)
+}
+
+#[tauri::command]
+async fn known_hosts_list(
+    path: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+) -> Result<Vec<KnownHost>, String> {
+    let host = host.as_deref().map(|host| (host, port.unwrap_or(22)));
+    known_hosts::list(&known_hosts_path(path), host).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn known_hosts_remove(
+    path: Option<String>,
+    host: String,
+    port: Option<u16>,
+) -> Result<KnownHostsEdit, String> {
+    known_hosts::remove_host(&known_hosts_path(path), &host, port.unwrap_or(22), None)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn known_hosts_scan(
+    path: Option<String>,
+    host: String,
+    port: Option<u16>,
+) -> Result<ScannedHostKey, String> {
+    let path = known_hosts_path(path);
+    tokio::task::spawn_blocking(move || known_hosts::scan(&path, &host, port.unwrap_or(22)))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn known_hosts_import(
+    path: Option<String>,
+    host: String,
+    port: Option<u16>,
+    fingerprint: String,
+    hash: Option<bool>,
+) -> Result<ScannedHostKey, String> {
+    let path = known_hosts_path(path);
+    tokio::task::spawn_blocking(move || {
+        let hash = hash.unwrap_or_else(|| known_hosts::prefers_hashing(&path));
+        known_hosts::import(&path, &host, port.unwrap_or(22), &fingerprint, hash)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn known_hosts_set_hashed(
+    path: Option<String>,
+    hashed: bool,
+    candidates: Option<Vec<String>>,
+) -> Result<KnownHostsEdit, String> {
+    known_hosts::set_hashed(
+        &known_hosts_path(path),
+        hashed,
+        &candidates.unwrap_or_default(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn ssh_connection_info(
+    session_id: String,
+    state: State<'_, AppState>,
+) -> Result<ConnectionTrace, String> {
+    let traces = state.connection_traces.lock().await;
+    traces
+        .get(&session_id)
+        .cloned()
+        .ok_or_else(|| "No connection attempt recorded for this session".to_string())
+}
+
+/// Answers an `ssh-host-key-request` prompt. No decision rejects the key.
+#[tauri::command]
+async fn ssh_host_key_response(
+    prompt_id: String,
+    decision: Option<HostKeyDecision>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let answer = serde_json::to_value(decision).map_err(|e| e.to_string())?;
+    state.prompts.respond(&prompt_id, answer).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn ssh_passphrase_response(
+    prompt_id: String,
+    passphrase: Option<String>,
+    remember: bool,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    // A missing passphrase means the user cancelled the prompt
+    let answer = match passphrase {
+        Some(passphrase) => serde_json::json!({ "passphrase": passphrase, "remember": remember }),
+        None => serde_json::Value::Null,
+    };
+
+    state.prompts.respond(&prompt_id, answer).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn ssh_forget_passphrases(state: State<'_, AppState>) -> Result<(), String> {
+    state.key_unlocker.clear().await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn agent_status(state: State<'_, AppState>) -> Result<AgentStatus, String> {
+    Ok(state.agent.status().await)
+}
+
+/// Loads a key into the built-in agent. `remember` saves the passphrase in the
+/// keyring so the key can be loaded again without asking.
+#[tauri::command]
+async fn agent_add_key(
+    path: String,
+    passphrase: Option<String>,
+    remember: Option<bool>,
+    options: Option<AddKeyOptions>,
+    state: State<'_, AppState>,
+) -> Result<AgentIdentity, String> {
+    let key_path = expand_tilde(&path);
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    let identity = state
+        .agent
+        .add_file(&key_path, passphrase.clone(), &options.unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let (Some(passphrase), Some(true)) = (passphrase, remember) {
+        credentials::save_key_passphrase(&key_path, &passphrase).map_err(|e| e.to_string())?;
+    }
+    Ok(identity)
+}
+
+#[tauri::command]
+async fn agent_remove_key(
+    fingerprint: String,
+    forget_passphrase: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let path = state
+        .agent
+        .remove(&fingerprint)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let (Some(path), Some(true)) = (path, forget_passphrase) {
+        credentials::forget_key_passphrase(&path).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn agent_remove_all(state: State<'_, AppState>) -> Result<(), String> {
+    state.agent.remove_all().await;
+    Ok(())
+}
+
 #[tauri::command]
 async fn ssh_send_input(
     session_id: String,
@@ -67,7 +402,9 @@ async fn ssh_disconnect(
     session_id: String,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
+    state.remote_editor.close_session(&session_id).await;
     let mut connections = state.connections.lock().await;
+    state.connection_traces.lock().await.remove(&session_id);
 
     if let Some(mut connection) = connections.remove(&session_id) {
         connection.disconnect().await.map_err(|e| e.to_string())
@@ -95,6 +432,42 @@ async fn ssh_resize(
     }
 }
 
+/// Runs a password manager command so the host editor can check it works.
+/// Only success or the error is reported; the secret stays in the backend.
+#[tauri::command]
+async fn test_secret_command(command: String, timeout_secs: Option<u64>) -> Result<(), String> {
+    let timeout = std::time::Duration::from_secs(timeout_secs.unwrap_or(credentials::DEFAULT_TIMEOUT_SECS));
+    credentials::run_secret_command("secret", &command, timeout)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn totp_set_secret(host_id: String, config: TotpConfig) -> Result<(), String> {
+    totp::save_secret(&host_id, &config).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn totp_remove_secret(host_id: String) -> Result<(), String> {
+    totp::delete_secret(&host_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn totp_has_secret(host_id: String) -> Result<bool, String> {
+    totp::load_secret(&host_id)
+        .map(|config| config.is_some())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn totp_current_code(host_id: String) -> Result<TotpCode, String> {
+    let config = totp::load_secret(&host_id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "No TOTP secret stored for this host".to_string())?;
+    config.current_code().map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn get_home_dir() -> Result<String, String> {
     dirs::home_dir()
@@ -108,8 +481,86 @@ async fn get_private_key_type(path: String) -> Result<String, String> {
 }
 
 #[tauri::command]
-async fn generate_keypair(output_path: String) -> Result<String, String> {
-    generate_ed25519_keypair(&output_path).map_err(|e| e.to_string())
+async fn inspect_key(path: String) -> Result<KeyInfo, String> {
+    keygen::inspect_key(Path::new(&path)).map_err(|e| e.to_string())
+}
+
+/// Lists the keys in `~/.ssh` and `extra_dirs`, with any problems found.
+#[tauri::command]
+async fn scan_ssh_keys(extra_dirs: Option<Vec<String>>) -> Result<Vec<InventoryEntry>, String> {
+    tokio::task::spawn_blocking(move || key_inventory::scan(&extra_dirs.unwrap_or_default()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn change_key_passphrase(
+    path: String,
+    old_passphrase: Option<String>,
+    new_passphrase: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let key_path = expand_tilde(&path);
+    let target = key_path.clone();
+
+    // Key derivation is deliberately slow; keep it off the async runtime
+    let backup = tokio::task::spawn_blocking(move || {
+        keygen::change_passphrase(&target, old_passphrase.as_deref(), new_passphrase.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+
+    state.key_unlocker.forget(&key_path).await;
+    Ok(backup.display().to_string())
+}
+
+#[tauri::command]
+async fn convert_key_format(
+    path: String,
+    format: ConvertFormat,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    let key_path = expand_tilde(&path);
+    let backup = tokio::task::spawn_blocking(move || {
+        keygen::convert_key(&key_path, format, passphrase.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+
+    Ok(backup.display().to_string())
+}
+
+#[tauri::command]
+async fn import_ppk_key(
+    ppk_path: String,
+    output_path: String,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    let ppk_path = expand_tilde(&ppk_path);
+    let output_path = expand_tilde(&output_path);
+    tokio::task::spawn_blocking(move || {
+        keygen::import_ppk(&ppk_path, &output_path, passphrase.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn generate_keypair(
+    output_path: String,
+    options: Option<KeyGenOptions>,
+) -> Result<String, String> {
+    let options = options.unwrap_or_default();
+
+    // RSA generation takes long enough to stall the async runtime
+    tokio::task::spawn_blocking(move || keygen::generate_keypair(&output_path, &options))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
 }
 
 
@@ -132,42 +583,508 @@ async fn sftp_list_directory(
     }
 }
 
+/// Renames or moves a remote file or directory.
+#[tauri::command]
+async fn sftp_rename(
+    session_id: String,
+    from: String,
+    to: String,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::rename(&connection, &from, &to).await.map_err(|e| e.to_string())
+}
+
+/// Deletes a remote file, link or (with `recursive`, non-empty) directory.
+#[tauri::command]
+async fn sftp_remove(
+    session_id: String,
+    path: String,
+    recursive: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::remove(&connection, &path, recursive.unwrap_or(false)).await.map_err(|e| e.to_string())
+}
+
+/// Creates a remote directory along with any missing parents.
+#[tauri::command]
+async fn sftp_mkdir(
+    session_id: String,
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::create_directory(&connection, &path).await.map_err(|e| e.to_string())
+}
+
+/// Sets permission bits, e.g. `0o755`, optionally on a whole tree.
+#[tauri::command]
+async fn sftp_chmod(
+    session_id: String,
+    path: String,
+    mode: u32,
+    recursive: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::chmod(&connection, &path, mode, recursive.unwrap_or(false)).await.map_err(|e| e.to_string())
+}
+
+/// Changes the numeric owner and/or group, optionally on a whole tree.
+#[tauri::command]
+async fn sftp_chown(
+    session_id: String,
+    path: String,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    recursive: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::chown(&connection, &path, uid, gid, recursive.unwrap_or(false)).await.map_err(|e| e.to_string())
+}
+
+/// Creates a remote symbolic link at `link_path` pointing at `target`.
+#[tauri::command]
+async fn sftp_symlink(
+    session_id: String,
+    target: String,
+    link_path: String,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::create_symlink(&connection, &target, &link_path).await.map_err(|e| e.to_string())
+}
+
+/// Reads the target of a remote symbolic link.
+#[tauri::command]
+async fn sftp_read_link(
+    session_id: String,
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    sftp::read_link(&connection, &path).await.map_err(|e| e.to_string())
+}
+
+/// Downloads a file, emitting `sftp-progress:{session_id}` events as it goes.
+/// Resolves with the transfer id once the copy has finished. Retrying a
+/// cancelled or failed transfer under the same id resumes it. An existing
+/// destination is handled by `options.conflict`, and the outcome is reported
+/// through `sftp-file:{session_id}`.
 #[tauri::command]
 async fn sftp_download(
     session_id: String,
     remote_path: String,
     local_path: String,
+    options: Option<TransferOptions>,
+    transfer_id: Option<String>,
+    app_handle: tauri::AppHandle,
     state: State<'_, AppState>,
-) -> Result<(), String> {
+) -> Result<String, String> {
     let connection = {
         let connections = state.connections.lock().await;
         connections.get(&session_id).cloned()
     };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
 
-    if let Some(connection) = connection {
-        download_file(&connection, &remote_path, &local_path).await.map_err(|e| e.to_string())
-    } else {
-        Err("Connection not found".to_string())
-    }
+    let transfer = state
+        .transfers
+        .begin(transfer_id, &session_id, TransferDirection::Download, &remote_path, &local_path, app_handle)
+        .await
+        .map_err(|e| e.to_string())?;
+    transfer.set_options(&options.unwrap_or_default());
+    let result = download_file(&connection, &remote_path, &local_path, &transfer).await;
+    state.transfers.end(&transfer, &result).await;
+    result.map(|_| transfer.id().to_string()).map_err(|e| e.to_string())
 }
 
+/// Uploads a file, emitting `sftp-progress:{session_id}` events as it goes.
+/// Resolves with the transfer id once the copy has finished. Retrying a
+/// cancelled or failed transfer under the same id resumes it. An existing
+/// destination is handled by `options.conflict`, and the outcome is reported
+/// through `sftp-file:{session_id}`.
 #[tauri::command]
 async fn sftp_upload(
     session_id: String,
     local_path: String,
     remote_path: String,
+    options: Option<TransferOptions>,
+    transfer_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    let transfer = state
+        .transfers
+        .begin(transfer_id, &session_id, TransferDirection::Upload, &local_path, &remote_path, app_handle)
+        .await
+        .map_err(|e| e.to_string())?;
+    transfer.set_options(&options.unwrap_or_default());
+    let result = upload_file(&connection, &local_path, &remote_path, &transfer).await;
+    state.transfers.end(&transfer, &result).await;
+    result.map(|_| transfer.id().to_string()).map_err(|e| e.to_string())
+}
+
+/// Downloads a remote directory tree; progress events cover the whole tree.
+#[tauri::command]
+async fn sftp_download_directory(
+    session_id: String,
+    remote_path: String,
+    local_path: String,
+    options: Option<TreeOptions>,
+    transfer_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    let transfer = state
+        .transfers
+        .begin(transfer_id, &session_id, TransferDirection::Download, &remote_path, &local_path, app_handle)
+        .await
+        .map_err(|e| e.to_string())?;
+    let options = options.unwrap_or_default();
+    transfer.set_options(&options.transfer);
+    let result = download_directory(
+        &connection,
+        &remote_path,
+        &local_path,
+        options,
+        &transfer,
+    )
+    .await;
+    state.transfers.end(&transfer, &result).await;
+    result.map(|_| transfer.id().to_string()).map_err(|e| e.to_string())
+}
+
+/// Uploads a local directory tree; progress events cover the whole tree.
+#[tauri::command]
+async fn sftp_upload_directory(
+    session_id: String,
+    local_path: String,
+    remote_path: String,
+    options: Option<TreeOptions>,
+    transfer_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    let transfer = state
+        .transfers
+        .begin(transfer_id, &session_id, TransferDirection::Upload, &local_path, &remote_path, app_handle)
+        .await
+        .map_err(|e| e.to_string())?;
+    let options = options.unwrap_or_default();
+    transfer.set_options(&options.transfer);
+    let result = upload_directory(
+        &connection,
+        &local_path,
+        &remote_path,
+        options,
+        &transfer,
+    )
+    .await;
+    state.transfers.end(&transfer, &result).await;
+    result.map(|_| transfer.id().to_string()).map_err(|e| e.to_string())
+}
+
+/// Stops a running upload or download and removes its partial file, unless
+/// `keep_partial` is set so a retry under the same id can resume it.
+#[tauri::command]
+async fn sftp_cancel(
+    transfer_id: String,
+    keep_partial: Option<bool>,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
+    state
+        .transfers
+        .cancel(&transfer_id, keep_partial.unwrap_or(false))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Answers an `sftp-conflict` prompt. No answer cancels the transfer.
+#[tauri::command]
+async fn sftp_conflict_response(
+    prompt_id: String,
+    answer: Option<ConflictAnswer>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let answer = serde_json::to_value(answer).map_err(|e| e.to_string())?;
+    state.prompts.respond(&prompt_id, answer).await.map_err(|e| e.to_string())
+}
+
+/// Dry run: what syncing the profile's directories would do.
+#[tauri::command]
+async fn sync_plan(
+    session_id: String,
+    profile: SyncProfile,
+    state: State<'_, AppState>,
+) -> Result<SyncPlan, String> {
     let connection = {
         let connections = state.connections.lock().await;
         connections.get(&session_id).cloned()
     };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
 
-    if let Some(connection) = connection {
-        upload_file(&connection, &local_path, &remote_path).await.map_err(|e| e.to_string())
-    } else {
-        Err("Connection not found".to_string())
+    sync::plan(&connection, &profile).await.map_err(|e| e.to_string())
+}
+
+/// Carries out `plan` (usually the reviewed dry run), or a fresh plan when none
+/// is given. Progress and cancellation work as for other transfers.
+#[tauri::command]
+async fn sync_run(
+    session_id: String,
+    profile: SyncProfile,
+    plan: Option<SyncPlan>,
+    transfer_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<String, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    let plan = match plan {
+        Some(plan) => plan,
+        None => sync::plan(&connection, &profile).await.map_err(|e| e.to_string())?,
+    };
+    let transfer = state
+        .transfers
+        .begin(
+            transfer_id,
+            &session_id,
+            TransferDirection::Sync,
+            &profile.local_path,
+            &profile.remote_path,
+            app_handle,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let result = sync::run(&connection, &profile, &plan, &transfer).await;
+    state.transfers.end(&transfer, &result).await;
+    result.map(|_| transfer.id().to_string()).map_err(|e| e.to_string())
+}
+
+/// Sync profiles saved for a host, by the host's id.
+#[tauri::command]
+async fn sync_profiles(host_id: String, app_handle: tauri::AppHandle) -> Result<Vec<SyncProfile>, String> {
+    sync::profiles(&app_handle, &host_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn sync_profile_save(
+    host_id: String,
+    profile: SyncProfile,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<SyncProfile>, String> {
+    sync::save_profile(&app_handle, &host_id, profile).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn sync_profile_delete(
+    host_id: String,
+    name: String,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<SyncProfile>, String> {
+    sync::delete_profile(&app_handle, &host_id, &name).map_err(|e| e.to_string())
+}
+
+/// Opens a remote file in a local editor; saves are uploaded as they happen
+/// and reported through `remote-edit:{session_id}` events.
+#[tauri::command]
+async fn remote_edit_open(
+    session_id: String,
+    remote_path: String,
+    editor: Option<String>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<EditSessionInfo, String> {
+    let connection = {
+        let connections = state.connections.lock().await;
+        connections.get(&session_id).cloned()
+    };
+    let connection = connection.ok_or_else(|| "Connection not found".to_string())?;
+
+    state
+        .remote_editor
+        .open(connection, &session_id, &remote_path, editor.as_deref(), app_handle)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remote_edit_list(state: State<'_, AppState>) -> Result<Vec<EditSessionInfo>, String> {
+    Ok(state.remote_editor.list().await)
+}
+
+/// Uploads the local copy now; `force` overwrites a conflicting remote change.
+#[tauri::command]
+async fn remote_edit_upload(
+    edit_id: String,
+    force: Option<bool>,
+    state: State<'_, AppState>,
+) -> Result<EditState, String> {
+    state
+        .remote_editor
+        .upload(&edit_id, force.unwrap_or(false))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remote_edit_close(edit_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state.remote_editor.close(&edit_id).await.map_err(|e| e.to_string())
+}
+
+/// Adds a transfer to the background queue. It runs on any open connection to
+/// the same host, now or after a restart.
+#[tauri::command]
+async fn transfer_queue_add(
+    session_id: String,
+    job: NewJob,
+    state: State<'_, AppState>,
+) -> Result<QueuedJob, String> {
+    state
+        .transfer_queue
+        .add(&session_id, job)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Changes are also announced through `transfer-queue` events.
+#[tauri::command]
+async fn transfer_queue_list(state: State<'_, AppState>) -> Result<QueueSnapshot, String> {
+    Ok(state.transfer_queue.snapshot().await)
+}
+
+/// Pauses one job, or the whole queue when no id is given.
+#[tauri::command]
+async fn transfer_queue_pause(
+    job_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .transfer_queue
+        .pause(job_id.as_deref())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Requeues paused, failed or cancelled jobs; all of them when no id is given.
+#[tauri::command]
+async fn transfer_queue_resume(
+    job_id: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .transfer_queue
+        .resume(job_id.as_deref())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn transfer_queue_move(
+    job_id: String,
+    position: usize,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .transfer_queue
+        .reorder(&job_id, position)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn transfer_queue_remove(job_id: String, state: State<'_, AppState>) -> Result<(), String> {
+    state.transfer_queue.remove(&job_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn transfer_queue_clear_finished(state: State<'_, AppState>) -> Result<(), String> {
+    state.transfer_queue.clear_finished().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn transfer_queue_set_limits(
+    limits: QueueLimits,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    state
+        .transfer_queue
+        .set_limits(limits)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Traffic and latency numbers for an SSH or local terminal session.
+#[tauri::command]
+async fn session_metrics(
+    session_id: String,
+    state: State<'_, AppState>,
+) -> Result<MetricsSnapshot, String> {
+    if let Some(connection) = state.connections.lock().await.get(&session_id) {
+        return Ok(connection.metrics().snapshot());
+    }
+    if let Some(connection) = state.local_connections.lock().await.get(&session_id) {
+        return Ok(connection.metrics().snapshot());
     }
+    Err("Connection not found".to_string())
 }
 
 // Local terminal commands
@@ -182,7 +1099,7 @@ async fn local_connect(
     let mut connection = LocalConnection::new(session_id.clone());
 
     connection
-        .spawn(shell, cwd, app_handle)
+        .spawn(shell, cwd, state.agent.socket_path(), app_handle)
         .await
         .map_err(|e| e.to_string())?;
 
@@ -244,6 +1161,10 @@ async fn local_resize(
 }
 
 fn main() {
+    let prompts = PromptBroker::default();
+    let connections = Arc::new(Mutex::new(HashMap::new()));
+    let transfers = TransferManager::new(prompts.clone());
+
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_store::Builder::default().build())
@@ -251,25 +1172,107 @@ fn main() {
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_process::init())
         .manage(AppState {
-            connections: Arc::new(Mutex::new(HashMap::new())),
+            connections: connections.clone(),
             local_connections: Arc::new(Mutex::new(HashMap::new())),
+            key_unlocker: KeyUnlocker::new(prompts.clone()),
+            agent: SshAgent::new(prompts.clone()),
+            transfer_queue: TransferQueue::new(connections, transfers.clone()),
+            transfers,
+            remote_editor: RemoteEditor::default(),
+            prompts,
+            connection_traces: Arc::new(Mutex::new(HashMap::new())),
+        })
+        .setup(|app| {
+            let agent = app.state::<AppState>().agent.clone();
+            // If this fails, local tabs simply start without SSH_AUTH_SOCK and
+            // agent_status reports no socket
+            let _ = tauri::async_runtime::block_on(agent.start(app.handle().clone()));
+
+            // An unreadable queue file is moved aside and leaves the queue
+            // empty rather than stopping the app
+            let queue = app.state::<AppState>().transfer_queue.clone();
+            let _ = tauri::async_runtime::block_on(queue.start(app.handle().clone()));
+            Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             ssh_connect,
             ssh_send_input,
             ssh_disconnect,
             ssh_resize,
+            ssh_session_metadata,
+            ssh_connection_info,
+            ssh_install_public_key,
+            authorized_keys_list,
+            authorized_keys_add,
+            authorized_keys_set_enabled,
+            authorized_keys_remove,
+            known_hosts_list,
+            known_hosts_remove,
+            known_hosts_scan,
+            known_hosts_import,
+            known_hosts_set_hashed,
+            ssh_passphrase_response,
+            ssh_host_key_response,
+            ssh_forget_passphrases,
+            agent_status,
+            agent_add_key,
+            agent_remove_key,
+            agent_remove_all,
+            test_secret_command,
+            totp_set_secret,
+            totp_remove_secret,
+            totp_has_secret,
+            totp_current_code,
             get_home_dir,
             get_private_key_type,
+            inspect_key,
+            scan_ssh_keys,
+            change_key_passphrase,
+            convert_key_format,
+            import_ppk_key,
             generate_keypair,
             sftp_list_directory,
             sftp_download,
             sftp_upload,
+            sftp_download_directory,
+            sftp_upload_directory,
+            sftp_cancel,
+            sftp_conflict_response,
+            sftp_rename,
+            sftp_remove,
+            sftp_mkdir,
+            sftp_chmod,
+            sftp_chown,
+            sftp_symlink,
+            sftp_read_link,
+            sync_plan,
+            sync_run,
+            sync_profiles,
+            sync_profile_save,
+            sync_profile_delete,
+            remote_edit_open,
+            remote_edit_list,
+            remote_edit_upload,
+            remote_edit_close,
+            transfer_queue_add,
+            transfer_queue_list,
+            transfer_queue_pause,
+            transfer_queue_resume,
+            transfer_queue_move,
+            transfer_queue_remove,
+            transfer_queue_clear_finished,
+            transfer_queue_set_limits,
+            session_metrics,
             local_connect,
             local_send_input,
             local_disconnect,
             local_resize,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app_handle, event| {
+            if let tauri::RunEvent::Exit = event {
+                app_handle.state::<AppState>().agent.stop();
+            }
+        });
 }
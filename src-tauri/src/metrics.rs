@@ -0,0 +1,120 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tokio::sync::watch;
+
+/// How often the per-session metrics event is emitted.
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Traffic counters shared between a connection's I/O tasks and the UI.
+pub struct SessionMetrics {
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    /// Latest round-trip time in microseconds; 0 until the first sample.
+    rtt_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub rtt_ms: Option<f64>,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MetricsEvent {
+    #[serde(flatten)]
+    snapshot: MetricsSnapshot,
+    /// Bytes per second since the previous event.
+    in_rate: f64,
+    out_rate: f64,
+}
+
+impl Default for SessionMetrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            rtt_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SessionMetrics {
+    pub fn record_read(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rtt(&self, rtt: Duration) {
+        // Keep 0 reserved for "no sample yet"
+        let micros = (rtt.as_micros() as u64).max(1);
+        self.rtt_micros.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let rtt = self.rtt_micros.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            rtt_ms: (rtt > 0).then(|| rtt as f64 / 1000.0),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Emits `session-metrics:{session_id}` every couple of seconds until the
+/// connection shuts down.
+pub fn spawn_reporter(
+    app_handle: tauri::AppHandle,
+    session_id: String,
+    metrics: Arc<SessionMetrics>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let event = format!("session-metrics:{}", session_id);
+        let mut interval = tokio::time::interval(REPORT_INTERVAL);
+        let mut previous = metrics.snapshot();
+        let mut previous_at = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => break,
+            }
+            if *shutdown_rx.borrow() {
+                break;
+            }
+
+            let snapshot = metrics.snapshot();
+            let elapsed = previous_at.elapsed().as_secs_f64().max(f64::EPSILON);
+            let payload = MetricsEvent {
+                in_rate: (snapshot.bytes_in - previous.bytes_in) as f64 / elapsed,
+                out_rate: (snapshot.bytes_out - previous.bytes_out) as f64 / elapsed,
+                snapshot: snapshot.clone(),
+            };
+            let _ = app_handle.emit(&event, payload);
+
+            previous = snapshot;
+            previous_at = Instant::now();
+        }
+    });
+}
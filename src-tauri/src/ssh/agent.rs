@@ -0,0 +1,526 @@
+//! SSH agent served on a unix socket owned by the app, so `ssh`, `git` and
+//! anything else started from a local tab can use the keys loaded here.
+//! Implements the parts of the agent protocol (draft-miller-ssh-agent) that
+//! OpenSSH clients and `ssh-add` use.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use signature::{SignatureEncoding, Signer};
+use ssh_encoding::{Decode, Encode, Reader};
+use ssh_key::private::KeypairData;
+use ssh_key::{Algorithm, HashAlg, PrivateKey, Signature};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tauri::Manager;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+
+use super::auth::expand_tilde;
+use super::credentials;
+use super::keygen;
+use super::prompt::PromptBroker;
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+
+const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+const SSH_AGENT_RSA_SHA2_512: u32 = 4;
+
+/// Same limit as OpenSSH's agent; anything larger is not a real request.
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// How often identities past their lifetime are dropped from memory.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Prefix of the per-process socket directories; the pid follows.
+const SOCKET_DIR_PREFIX: &str = "gterm-agent-";
+
+/// The frontend's private key list, saved by the store plugin as
+/// `{"privateKeys": [{"path": ..}, ..]}`.
+const PRIVATE_KEYS_FILE: &str = "privateKeys.json";
+
+struct Identity {
+    key: PrivateKey,
+    path: Option<PathBuf>,
+    confirm: bool,
+    expires_at: Option<Instant>,
+}
+
+impl Identity {
+    fn fingerprint(&self) -> String {
+        self.key.fingerprint(HashAlg::Sha256).to_string()
+    }
+
+    fn public_blob(&self) -> Result<Vec<u8>> {
+        Ok(self.key.public_key().to_bytes()?)
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn summary(&self) -> AgentIdentity {
+        AgentIdentity {
+            fingerprint: self.fingerprint(),
+            key_type: self.key.algorithm().as_str().to_string(),
+            comment: self.key.comment().to_string(),
+            path: self.path.as_ref().map(|p| p.display().to_string()),
+            confirm: self.confirm,
+            expires_in_secs: self
+                .expires_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+        }
+    }
+}
+
+/// An identity as shown in the UI; never includes key material.
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentIdentity {
+    pub fingerprint: String,
+    pub key_type: String,
+    pub comment: String,
+    /// Key file it was loaded from; `None` for keys added with `ssh-add`.
+    pub path: Option<String>,
+    pub confirm: bool,
+    pub expires_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentStatus {
+    /// Value exported as `SSH_AUTH_SOCK`; `None` if the agent failed to start.
+    pub socket_path: Option<String>,
+    pub identities: Vec<AgentIdentity>,
+}
+
+/// Per-identity constraints, matching `ssh-add -c` and `ssh-add -t`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AddKeyOptions {
+    /// Ask the user before every signature made with this key.
+    pub confirm: bool,
+    /// Forget the key after this many seconds.
+    pub lifetime_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ConfirmRequest {
+    prompt_id: String,
+    fingerprint: String,
+    comment: String,
+    key_path: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct SshAgent {
+    identities: Arc<Mutex<Vec<Identity>>>,
+    socket_path: Arc<OnceLock<PathBuf>>,
+    prompts: PromptBroker,
+}
+
+impl SshAgent {
+    pub fn new(prompts: PromptBroker) -> Self {
+        Self {
+            prompts,
+            ..Default::default()
+        }
+    }
+
+    /// Binds the socket in a private per-process directory and starts serving
+    /// the keys from the private key list. Directories left behind by earlier
+    /// runs that didn't exit cleanly are removed first.
+    pub async fn start(&self, app_handle: tauri::AppHandle) -> Result<PathBuf> {
+        let parent = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+        remove_stale_dirs(&parent);
+        let dir = parent.join(format!("{}{}", SOCKET_DIR_PREFIX, std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+
+        let socket_path = dir.join("agent.sock");
+        if socket_path.exists() {
+            std::fs::remove_file(&socket_path)?;
+        }
+        let listener = UnixListener::bind(&socket_path)?;
+        self.socket_path
+            .set(socket_path.clone())
+            .map_err(|_| anyhow!("The SSH agent is already running"))?;
+
+        let agent = self.clone();
+        let keys_handle = app_handle.clone();
+        tokio::spawn(async move { agent.load_saved_keys(&keys_handle).await });
+
+        let agent = self.clone();
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            let agent = agent.clone();
+                            let app_handle = app_handle.clone();
+                            tokio::spawn(async move { agent.serve(stream, app_handle).await });
+                        }
+                        // Usually out of file descriptors; give clients time to close some
+                        Err(_) => tokio::time::sleep(ACCEPT_RETRY_DELAY).await,
+                    },
+                    _ = sweep.tick() => agent.remove_expired().await,
+                }
+            }
+        });
+
+        Ok(socket_path)
+    }
+
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.get().map(PathBuf::as_path)
+    }
+
+    /// Removes the socket and its directory; called when the app exits.
+    pub fn stop(&self) {
+        if let Some(socket_path) = self.socket_path() {
+            let _ = std::fs::remove_file(socket_path);
+            if let Some(dir) = socket_path.parent() {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+    }
+
+    /// Adds every key from the private key list that opens without asking:
+    /// unencrypted ones and those with a passphrase saved in the keyring.
+    /// The rest can still be added by hand.
+    async fn load_saved_keys(&self, app_handle: &tauri::AppHandle) {
+        let Ok(dir) = app_handle.path().app_data_dir() else {
+            return;
+        };
+        let Ok(contents) = tokio::fs::read_to_string(dir.join(PRIVATE_KEYS_FILE)).await else {
+            return;
+        };
+        let Ok(saved) = serde_json::from_str::<serde_json::Value>(&contents) else {
+            return;
+        };
+        let paths = saved["privateKeys"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|key| key["path"].as_str())
+            .map(expand_tilde);
+        for path in paths {
+            let _ = self.add_file(&path, None, &AddKeyOptions::default()).await;
+        }
+    }
+
+    pub async fn status(&self) -> AgentStatus {
+        AgentStatus {
+            socket_path: self.socket_path().map(|p| p.display().to_string()),
+            identities: self.identities().await,
+        }
+    }
+
+    pub async fn identities(&self) -> Vec<AgentIdentity> {
+        self.remove_expired().await;
+        self.identities
+            .lock()
+            .await
+            .iter()
+            .map(Identity::summary)
+            .collect()
+    }
+
+    /// Loads a key file into the agent. Without `passphrase`, one saved in the
+    /// keyring for this path is used.
+    pub async fn add_file(
+        &self,
+        path: &Path,
+        passphrase: Option<String>,
+        options: &AddKeyOptions,
+    ) -> Result<AgentIdentity> {
+        let key_path = path.to_path_buf();
+        let key = tokio::task::spawn_blocking(move || {
+            let passphrase = match passphrase {
+                Some(passphrase) => Some(passphrase),
+                None if keygen::is_key_encrypted(&key_path).unwrap_or(false) => {
+                    credentials::saved_key_passphrase(&key_path)?
+                }
+                None => None,
+            };
+            keygen::load_private_key(&key_path, passphrase.as_deref())
+        })
+        .await??;
+
+        Ok(self.add(key, Some(path.to_path_buf()), options).await)
+    }
+
+    /// Adds a key, replacing an identity with the same public key.
+    pub async fn add(
+        &self,
+        key: PrivateKey,
+        path: Option<PathBuf>,
+        options: &AddKeyOptions,
+    ) -> AgentIdentity {
+        let identity = Identity {
+            key,
+            path,
+            confirm: options.confirm,
+            expires_at: options
+                .lifetime_secs
+                .map(|secs| Instant::now() + Duration::from_secs(secs)),
+        };
+        let summary = identity.summary();
+
+        let mut identities = self.identities.lock().await;
+        identities.retain(|existing| existing.key.public_key() != identity.key.public_key());
+        identities.push(identity);
+        summary
+    }
+
+    /// Removes the identity with this `SHA256:` fingerprint, returning the
+    /// file it came from.
+    pub async fn remove(&self, fingerprint: &str) -> Result<Option<PathBuf>> {
+        let mut identities = self.identities.lock().await;
+        let index = identities
+            .iter()
+            .position(|identity| identity.fingerprint() == fingerprint)
+            .ok_or_else(|| anyhow!("No identity with fingerprint {} in the agent", fingerprint))?;
+        Ok(identities.remove(index).path)
+    }
+
+    pub async fn remove_all(&self) {
+        self.identities.lock().await.clear();
+    }
+
+    async fn remove_expired(&self) {
+        let now = Instant::now();
+        self.identities
+            .lock()
+            .await
+            .retain(|identity| !identity.is_expired(now));
+    }
+
+    /// Answers requests on one client connection until it closes.
+    async fn serve(&self, mut stream: UnixStream, app_handle: tauri::AppHandle) {
+        loop {
+            let Ok(len) = stream.read_u32().await else {
+                return;
+            };
+            let len = len as usize;
+            if len == 0 || len > MAX_MESSAGE_LEN {
+                return;
+            }
+            let mut request = vec![0u8; len];
+            if stream.read_exact(&mut request).await.is_err() {
+                return;
+            }
+
+            // Failures are reported to the client without detail, as OpenSSH does
+            let response = self
+                .handle(&request, &app_handle)
+                .await
+                .unwrap_or_else(|_| vec![SSH_AGENT_FAILURE]);
+
+            let mut framed = (response.len() as u32).to_be_bytes().to_vec();
+            framed.extend_from_slice(&response);
+            if stream.write_all(&framed).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn handle(&self, request: &[u8], app_handle: &tauri::AppHandle) -> Result<Vec<u8>> {
+        self.remove_expired().await;
+        let (&kind, mut body) = request
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty agent request"))?;
+
+        match kind {
+            SSH_AGENTC_REQUEST_IDENTITIES => self.identities_answer().await,
+            SSH_AGENTC_SIGN_REQUEST => self.sign_request(&mut body, app_handle).await,
+            SSH_AGENTC_ADD_IDENTITY | SSH_AGENTC_ADD_ID_CONSTRAINED => {
+                let keypair = KeypairData::decode(&mut body)?;
+                let comment = String::decode(&mut body)?;
+                let options = decode_constraints(&mut body)?;
+                let key = PrivateKey::new(keypair, comment)?;
+                self.add(key, None, &options).await;
+                Ok(vec![SSH_AGENT_SUCCESS])
+            }
+            SSH_AGENTC_REMOVE_IDENTITY => {
+                let blob = Vec::<u8>::decode(&mut body)?;
+                let mut identities = self.identities.lock().await;
+                let before = identities.len();
+                identities.retain(|identity| identity.public_blob().ok().as_ref() != Some(&blob));
+                Ok(vec![if identities.len() < before {
+                    SSH_AGENT_SUCCESS
+                } else {
+                    SSH_AGENT_FAILURE
+                }])
+            }
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => {
+                self.remove_all().await;
+                Ok(vec![SSH_AGENT_SUCCESS])
+            }
+            // Locking, smartcards and extensions are not supported
+            _ => Ok(vec![SSH_AGENT_FAILURE]),
+        }
+    }
+
+    async fn identities_answer(&self) -> Result<Vec<u8>> {
+        let identities = self.identities.lock().await;
+        let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+        (identities.len() as u32).encode(&mut response)?;
+        for identity in identities.iter() {
+            identity.public_blob()?.encode(&mut response)?;
+            identity.key.comment().encode(&mut response)?;
+        }
+        Ok(response)
+    }
+
+    async fn sign_request(
+        &self,
+        body: &mut &[u8],
+        app_handle: &tauri::AppHandle,
+    ) -> Result<Vec<u8>> {
+        let blob = Vec::<u8>::decode(body)?;
+        let data = Vec::<u8>::decode(body)?;
+        let flags = u32::decode(body)?;
+
+        // Take a copy so the list isn't locked while the user decides
+        let (key, confirm) = {
+            let identities = self.identities.lock().await;
+            let identity = identities
+                .iter()
+                .find(|identity| identity.public_blob().ok().as_ref() == Some(&blob))
+                .ok_or_else(|| anyhow!("Unknown identity"))?;
+            let confirm = identity.confirm.then(|| ConfirmRequest {
+                prompt_id: String::new(),
+                fingerprint: identity.fingerprint(),
+                comment: identity.key.comment().to_string(),
+                key_path: identity.path.as_ref().map(|p| p.display().to_string()),
+            });
+            (identity.key.clone(), confirm)
+        };
+
+        if let Some(request) = confirm {
+            let approved: Option<bool> = self
+                .prompts
+                .ask(app_handle, "ssh-agent-confirm", |prompt_id| {
+                    ConfirmRequest {
+                        prompt_id,
+                        ..request
+                    }
+                })
+                .await;
+            if approved != Some(true) {
+                return Err(anyhow!("Signature refused by the user"));
+            }
+        }
+
+        let signature = sign(&key, &data, flags)?;
+        let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+        signature.encode_prefixed(&mut response)?;
+        Ok(response)
+    }
+}
+
+/// Deletes socket directories of earlier runs whose process is gone. Only
+/// directories owned by this user are touched.
+fn remove_stale_dirs(parent: &Path) {
+    let Ok(entries) = std::fs::read_dir(parent) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(SOCKET_DIR_PREFIX))
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == std::process::id() || is_running(pid) {
+            continue;
+        }
+        let path = entry.path();
+        if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_dir() && is_own(&meta)) {
+            let _ = std::fs::remove_dir_all(&path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether the process exists; EPERM means it does
+    // but belongs to someone else
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn is_own(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.uid() == unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+fn is_own(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+fn decode_constraints(body: &mut &[u8]) -> Result<AddKeyOptions> {
+    let mut options = AddKeyOptions::default();
+    while !body.is_finished() {
+        match u8::decode(body)? {
+            SSH_AGENT_CONSTRAIN_LIFETIME => options.lifetime_secs = Some(u32::decode(body)?.into()),
+            SSH_AGENT_CONSTRAIN_CONFIRM => options.confirm = true,
+            other => return Err(anyhow!("Unsupported agent constraint {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+/// Signs `data`, honouring the client's choice of RSA signature hash.
+fn sign(key: &PrivateKey, data: &[u8], flags: u32) -> Result<Signature> {
+    let KeypairData::Rsa(keypair) = key.key_data() else {
+        return Ok(key.try_sign(data)?);
+    };
+
+    use rsa::pkcs1v15::SigningKey;
+    let private = keygen::rsa_private_key(keypair)?;
+    let (hash, bytes) = if flags & SSH_AGENT_RSA_SHA2_512 != 0 {
+        let signature = SigningKey::<Sha512>::new(private).try_sign(data)?;
+        (Some(HashAlg::Sha512), signature.to_vec())
+    } else if flags & SSH_AGENT_RSA_SHA2_256 != 0 {
+        let signature = SigningKey::<Sha256>::new(private).try_sign(data)?;
+        (Some(HashAlg::Sha256), signature.to_vec())
+    } else {
+        let signature = SigningKey::<Sha1>::new(private).try_sign(data)?;
+        (None, signature.to_vec())
+    };
+    Ok(Signature::new(Algorithm::Rsa { hash }, bytes)?)
+}
@@ -0,0 +1,646 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use ssh2::{ErrorCode, KeyboardInteractivePrompt, Prompt, Session};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tauri::Emitter;
+use tokio::sync::Mutex;
+
+use super::credentials::SecretCommands;
+use super::keygen;
+use super::prompt::PromptBroker;
+use super::totp::{self, TotpResponder};
+
+/// Identity files tried when a host profile does not name any, in the same
+/// order OpenSSH uses.
+const DEFAULT_IDENTITY_FILES: [&str; 3] = ["~/.ssh/id_ed25519", "~/.ssh/id_ecdsa", "~/.ssh/id_rsa"];
+
+/// How many times the user is asked for a key's passphrase before giving up on that key.
+const MAX_PASSPHRASE_PROMPTS: u32 = 3;
+
+/// libssh2 reports a wrong passphrase as a failure to read the key file, or,
+/// depending on the key format and backend, as a failed key file auth.
+const LIBSSH2_ERROR_FILE: i32 = -16;
+const LIBSSH2_ERROR_KEYFILE_AUTH_FAILED: i32 = -48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    Agent,
+    PublicKey,
+    Certificate,
+    Password,
+    KeyboardInteractive,
+}
+
+impl AuthMethod {
+    /// Order used when the host profile has no `PreferredAuthentications`.
+    pub const DEFAULT_ORDER: [AuthMethod; 5] = [
+        AuthMethod::Agent,
+        AuthMethod::PublicKey,
+        AuthMethod::Certificate,
+        AuthMethod::Password,
+        AuthMethod::KeyboardInteractive,
+    ];
+
+    /// Name of the userauth method this maps to on the wire.
+    pub fn protocol_name(self) -> &'static str {
+        match self {
+            AuthMethod::Agent | AuthMethod::PublicKey | AuthMethod::Certificate => "publickey",
+            AuthMethod::Password => "password",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+        }
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AuthMethod::Agent => "agent",
+            AuthMethod::PublicKey => "publickey",
+            AuthMethod::Certificate => "certificate",
+            AuthMethod::Password => "password",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Credentials and method order for a single connection attempt.
+///
+/// Deliberately not `Debug`, so secrets cannot end up in log output.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthOptions {
+    /// Methods to try, in order. Empty means `AuthMethod::DEFAULT_ORDER`.
+    pub methods: Vec<AuthMethod>,
+    pub identity_files: Vec<String>,
+    pub passphrase: Option<String>,
+    pub certificate_file: Option<String>,
+    pub password: Option<String>,
+    /// Host profile id, used to look up secrets kept in the keyring for it.
+    pub host_id: Option<String>,
+    /// Regex for keyboard-interactive prompts to answer with the host's TOTP
+    /// code. Defaults to `totp::DEFAULT_PROMPT_PATTERN`.
+    pub verification_code_pattern: Option<String>,
+    /// Password manager commands that supply the password or passphrase.
+    pub secret_commands: SecretCommands,
+}
+
+impl AuthOptions {
+    fn method_order(&self) -> &[AuthMethod] {
+        if self.methods.is_empty() {
+            &AuthMethod::DEFAULT_ORDER
+        } else {
+            &self.methods
+        }
+    }
+
+    fn identity_paths(&self) -> Vec<PathBuf> {
+        if self.identity_files.is_empty() {
+            DEFAULT_IDENTITY_FILES
+                .iter()
+                .map(|p| expand_tilde(p))
+                .filter(|p| p.exists())
+                .collect()
+        } else {
+            self.identity_files
+                .iter()
+                .map(|p| expand_tilde(p))
+                .collect()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttemptResult {
+    Accepted,
+    Rejected,
+    Skipped,
+}
+
+/// One step of the fallback chain, kept for error reporting.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthAttempt {
+    pub method: AuthMethod,
+    /// Key file or agent identity the attempt used, if any.
+    pub target: Option<String>,
+    pub result: AttemptResult,
+    pub message: Option<String>,
+}
+
+impl AuthAttempt {
+    fn new(
+        method: AuthMethod,
+        target: Option<String>,
+        result: AttemptResult,
+        message: Option<String>,
+    ) -> Self {
+        Self {
+            method,
+            target,
+            result,
+            message,
+        }
+    }
+
+    fn skipped(method: AuthMethod, reason: &str) -> Self {
+        Self::new(
+            method,
+            None,
+            AttemptResult::Skipped,
+            Some(reason.to_string()),
+        )
+    }
+}
+
+impl fmt::Display for AuthAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.method)?;
+        if let Some(ref target) = self.target {
+            write!(f, " {}", target)?;
+        }
+        let result = match self.result {
+            AttemptResult::Accepted => "accepted",
+            AttemptResult::Rejected => "rejected",
+            AttemptResult::Skipped => "skipped",
+        };
+        match self.message {
+            Some(ref message) => write!(f, " ({}: {})", result, message),
+            None => write!(f, " ({})", result),
+        }
+    }
+}
+
+/// Returned when every configured method has been exhausted.
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+pub struct AuthFailure {
+    pub username: String,
+    pub attempts: Vec<AuthAttempt>,
+    /// Methods the server listed in its `SSH_MSG_USERAUTH_FAILURE` reply.
+    pub server_methods: Vec<String>,
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authentication failed for user '{}'", self.username)?;
+        if self.server_methods.is_empty() {
+            write!(f, "; server did not list any methods")?;
+        } else {
+            write!(f, "; server accepts: {}", self.server_methods.join(", "))?;
+        }
+        if self.attempts.is_empty() {
+            write!(f, "; nothing was attempted")
+        } else {
+            let attempts: Vec<String> = self.attempts.iter().map(|a| a.to_string()).collect();
+            write!(f, "; tried: {}", attempts.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PassphraseRequest {
+    prompt_id: String,
+    key_path: String,
+    attempt: u32,
+    max_attempts: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PassphraseAnswer {
+    pub passphrase: String,
+    /// Keep the passphrase in memory for the rest of the app session.
+    #[serde(default)]
+    pub remember: bool,
+}
+
+/// Asks the frontend for key passphrases when a connection needs one, and
+/// remembers the ones the user chose to keep until the app exits.
+#[derive(Clone, Default)]
+pub struct KeyUnlocker {
+    prompts: PromptBroker,
+    cache: Arc<Mutex<HashMap<PathBuf, String>>>,
+}
+
+impl KeyUnlocker {
+    pub fn new(prompts: PromptBroker) -> Self {
+        Self {
+            prompts,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn cached(&self, key_path: &Path) -> Option<String> {
+        self.cache.lock().await.get(key_path).cloned()
+    }
+
+    async fn remember(&self, key_path: &Path, passphrase: String) {
+        self.cache
+            .lock()
+            .await
+            .insert(key_path.to_path_buf(), passphrase);
+    }
+
+    pub async fn forget(&self, key_path: &Path) {
+        self.cache.lock().await.remove(key_path);
+    }
+
+    /// Emits `ssh-passphrase-request:{session_id}` and waits for the answer.
+    async fn prompt(
+        &self,
+        app_handle: &tauri::AppHandle,
+        session_id: &str,
+        key_path: &Path,
+        attempt: u32,
+    ) -> Option<PassphraseAnswer> {
+        let event = format!("ssh-passphrase-request:{}", session_id);
+        self.prompts
+            .ask(app_handle, &event, |prompt_id| PassphraseRequest {
+                prompt_id,
+                key_path: key_path.display().to_string(),
+                attempt,
+                max_attempts: MAX_PASSPHRASE_PROMPTS,
+            })
+            .await
+    }
+
+    /// Drops every remembered passphrase.
+    pub async fn clear(&self) {
+        self.cache.lock().await.clear();
+    }
+}
+
+/// Answers keyboard-interactive prompts: one-time code prompts get the
+/// current TOTP code, other hidden prompts get the password.
+struct InteractivePrompter<'a> {
+    password: Option<&'a str>,
+    totp: Option<&'a TotpResponder>,
+}
+
+impl KeyboardInteractivePrompt for InteractivePrompter<'_> {
+    fn prompt<'a>(
+        &mut self,
+        _username: &str,
+        _instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        prompts
+            .iter()
+            .map(|p| match self.totp {
+                Some(totp) if totp.matches(&p.text) => totp.code().unwrap_or_default(),
+                _ if p.echo => String::new(),
+                _ => self.password.unwrap_or_default().to_string(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BannerEvent {
+    server_id: Option<String>,
+    auth_banner: Option<String>,
+}
+
+fn emit_banner(session: &Session, app_handle: &tauri::AppHandle, session_id: &str) {
+    let _ = app_handle.emit(
+        &format!("ssh-banner:{}", session_id),
+        BannerEvent {
+            server_id: session.banner().map(|s| s.to_string()),
+            auth_banner: session.userauth_banner().ok().flatten().map(|s| s.to_string()),
+        },
+    );
+}
+
+/// Runs the configured methods in order until the server accepts one.
+///
+/// Methods the server does not offer are skipped without a round trip, so the
+/// returned attempts (or the `AuthFailure` error) describe exactly what
+/// happened. Encrypted keys without a working passphrase are unlocked by
+/// prompting the user through `unlocker`. With `announce_banner`, the server's
+/// banner is sent as `ssh-banner:{session_id}` before anything is prompted.
+pub async fn authenticate(
+    session: &Session,
+    username: &str,
+    options: &AuthOptions,
+    unlocker: &KeyUnlocker,
+    app_handle: &tauri::AppHandle,
+    session_id: &str,
+    announce_banner: bool,
+) -> Result<Vec<AuthAttempt>> {
+    // Sends a "none" request, which also succeeds outright on servers that
+    // allow unauthenticated logins.
+    let methods = session.auth_methods(username);
+    // The banner came with that reply; a legal notice must be on screen
+    // before any passphrase, password or code is asked for
+    if announce_banner {
+        emit_banner(session, app_handle, session_id);
+    }
+    let server_methods: Vec<String> = match methods {
+        Ok(list) => list
+            .split(',')
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .collect(),
+        Err(_) if session.authenticated() => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("Failed to query authentication methods: {}", e)),
+    };
+    if session.authenticated() {
+        return Ok(Vec::new());
+    }
+
+    let mut chain = AuthChain {
+        session,
+        username,
+        options,
+        unlocker,
+        app_handle,
+        session_id,
+        attempts: Vec::new(),
+    };
+
+    for &method in options.method_order() {
+        if !server_methods.iter().any(|m| m == method.protocol_name()) {
+            chain.skip(method, "not offered by server");
+            continue;
+        }
+
+        match method {
+            AuthMethod::Agent => chain.try_agent(),
+            AuthMethod::PublicKey => {
+                let keys = options.identity_paths();
+                if keys.is_empty() {
+                    chain.skip(method, "no identity files");
+                }
+                for key in keys {
+                    chain.try_key(method, None, &key).await;
+                    if session.authenticated() {
+                        break;
+                    }
+                }
+            }
+            AuthMethod::Certificate => {
+                let pairs = certificate_pairs(options);
+                if pairs.is_empty() {
+                    chain.skip(method, "no certificate found");
+                }
+                for (cert, key) in pairs {
+                    chain.try_key(method, Some(&cert), &key).await;
+                    if session.authenticated() {
+                        break;
+                    }
+                }
+            }
+            AuthMethod::Password => match options.password {
+                Some(ref password) => {
+                    let result = session.userauth_password(username, password);
+                    chain.record(method, None, result);
+                }
+                None => chain.skip(method, "no password configured"),
+            },
+            AuthMethod::KeyboardInteractive => {
+                let totp = match options.host_id {
+                    Some(ref host_id) => match totp::load_secret(host_id) {
+                        Ok(Some(config)) => match TotpResponder::new(
+                            config,
+                            options.verification_code_pattern.as_deref(),
+                        ) {
+                            Ok(responder) => Some(responder),
+                            Err(e) => {
+                                chain.skip(method, &format!("invalid TOTP settings: {}", e));
+                                continue;
+                            }
+                        },
+                        Ok(None) => None,
+                        Err(e) => {
+                            chain.skip(method, &format!("could not read TOTP secret: {}", e));
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                if options.password.is_none() && totp.is_none() {
+                    chain.skip(method, "no password or TOTP secret configured");
+                    continue;
+                }
+                let mut prompter = InteractivePrompter {
+                    password: options.password.as_deref(),
+                    totp: totp.as_ref(),
+                };
+                let result = session.userauth_keyboard_interactive(username, &mut prompter);
+                chain.record(method, None, result);
+            }
+        }
+
+        if session.authenticated() {
+            return Ok(chain.attempts);
+        }
+    }
+
+    Err(AuthFailure {
+        username: username.to_string(),
+        attempts: chain.attempts,
+        server_methods,
+    }
+    .into())
+}
+
+/// State shared by the steps of one `authenticate` call.
+struct AuthChain<'a> {
+    session: &'a Session,
+    username: &'a str,
+    options: &'a AuthOptions,
+    unlocker: &'a KeyUnlocker,
+    app_handle: &'a tauri::AppHandle,
+    session_id: &'a str,
+    attempts: Vec<AuthAttempt>,
+}
+
+impl AuthChain<'_> {
+    fn skip(&mut self, method: AuthMethod, reason: &str) {
+        self.attempts.push(AuthAttempt::skipped(method, reason));
+    }
+
+    fn record(
+        &mut self,
+        method: AuthMethod,
+        target: Option<String>,
+        result: Result<(), ssh2::Error>,
+    ) {
+        self.attempts.push(attempt_from(method, target, result));
+    }
+
+    fn try_agent(&mut self) {
+        let mut agent = match self.session.agent() {
+            Ok(agent) => agent,
+            Err(e) => {
+                self.skip(AuthMethod::Agent, e.message());
+                return;
+            }
+        };
+        if let Err(e) = agent.connect() {
+            self.skip(
+                AuthMethod::Agent,
+                &format!("no agent available ({})", e.message()),
+            );
+            return;
+        }
+
+        let identities = agent
+            .list_identities()
+            .and_then(|_| agent.identities())
+            .unwrap_or_default();
+        if identities.is_empty() {
+            self.skip(AuthMethod::Agent, "agent has no identities");
+        }
+        for identity in identities {
+            let result = agent.userauth(self.username, &identity);
+            self.record(
+                AuthMethod::Agent,
+                Some(identity.comment().to_string()),
+                result,
+            );
+            if self.session.authenticated() {
+                break;
+            }
+        }
+        let _ = agent.disconnect();
+    }
+
+    /// Tries one private key, prompting for its passphrase when the key is
+    /// encrypted and neither the profile nor the cache has one that works.
+    async fn try_key(&mut self, method: AuthMethod, public_key: Option<&Path>, private_key: &Path) {
+        let target = Some(private_key.display().to_string());
+        if !private_key.exists() {
+            self.attempts.push(AuthAttempt::new(
+                method,
+                target,
+                AttemptResult::Skipped,
+                Some("file not found".to_string()),
+            ));
+            return;
+        }
+
+        let encrypted = keygen::is_key_encrypted(private_key).unwrap_or(false);
+        let mut passphrase = match self.options.passphrase {
+            Some(ref passphrase) => Some(passphrase.clone()),
+            None => self.unlocker.cached(private_key).await,
+        };
+        let mut prompts = 0;
+
+        loop {
+            let mut remember = false;
+            if encrypted && passphrase.is_none() {
+                if prompts == MAX_PASSPHRASE_PROMPTS {
+                    self.attempts.push(AuthAttempt::new(
+                        method,
+                        target,
+                        AttemptResult::Rejected,
+                        Some("too many incorrect passphrases".to_string()),
+                    ));
+                    return;
+                }
+                prompts += 1;
+                match self
+                    .unlocker
+                    .prompt(self.app_handle, self.session_id, private_key, prompts)
+                    .await
+                {
+                    Some(answer) => {
+                        passphrase = Some(answer.passphrase);
+                        remember = answer.remember;
+                    }
+                    None => {
+                        self.attempts.push(AuthAttempt::new(
+                            method,
+                            target,
+                            AttemptResult::Skipped,
+                            Some("passphrase prompt cancelled".to_string()),
+                        ));
+                        return;
+                    }
+                }
+            }
+
+            let result = self.session.userauth_pubkey_file(
+                self.username,
+                public_key,
+                private_key,
+                passphrase.as_deref(),
+            );
+            match result {
+                Err(ref e) if encrypted && is_decrypt_error(e) => {
+                    // Wrong passphrase: drop it (and any stale cache entry) and ask again
+                    self.unlocker.forget(private_key).await;
+                    passphrase = None;
+                }
+                Ok(()) => {
+                    if let (true, Some(passphrase)) = (remember, passphrase) {
+                        self.unlocker.remember(private_key, passphrase).await;
+                    }
+                    self.record(method, target, Ok(()));
+                    return;
+                }
+                Err(e) => {
+                    self.record(method, target, Err(e));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Pairs each certificate with the private key it was issued for. An explicit
+/// certificate goes with the first identity file; otherwise `<key>-cert.pub`
+/// files next to the identities are picked up, as OpenSSH does.
+fn certificate_pairs(options: &AuthOptions) -> Vec<(PathBuf, PathBuf)> {
+    let keys = options.identity_paths();
+    match options.certificate_file {
+        Some(ref cert) => keys
+            .into_iter()
+            .next()
+            .map(|key| vec![(expand_tilde(cert), key)])
+            .unwrap_or_default(),
+        None => keys
+            .into_iter()
+            .filter_map(|key| {
+                let cert = PathBuf::from(format!("{}-cert.pub", key.display()));
+                cert.exists().then_some((cert, key))
+            })
+            .collect(),
+    }
+}
+
+fn attempt_from(
+    method: AuthMethod,
+    target: Option<String>,
+    result: Result<(), ssh2::Error>,
+) -> AuthAttempt {
+    match result {
+        Ok(()) => AuthAttempt::new(method, target, AttemptResult::Accepted, None),
+        Err(e) => AuthAttempt::new(
+            method,
+            target,
+            AttemptResult::Rejected,
+            Some(e.message().to_string()),
+        ),
+    }
+}
+
+fn is_decrypt_error(e: &ssh2::Error) -> bool {
+    matches!(
+        e.code(),
+        ErrorCode::Session(LIBSSH2_ERROR_FILE | LIBSSH2_ERROR_KEYFILE_AUTH_FAILED)
+    )
+}
+
+pub fn expand_tilde(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir()
+            .map(|home| home.join(rest))
+            .unwrap_or_else(|| PathBuf::from(path)),
+        None => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,508 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use ssh2::Session;
+use ssh_key::{HashAlg, PublicKey};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use super::auth::{self, AuthMethod, AuthOptions, KeyUnlocker};
+use super::connection::{SessionMetadata, SshConnection};
+use super::keygen;
+use super::known_hosts::{self, HostKeyStatus};
+use super::remote::{self, shell_quote};
+
+/// Timeout for the verification login, in milliseconds.
+const VERIFY_TIMEOUT_MS: u32 = 15_000;
+
+/// Prints the remote file, or nothing when it does not exist yet.
+const READ_SCRIPT: &str = r#"f="$HOME/.ssh/authorized_keys"
+[ -f "$f" ] || exit 0
+cat "$f""#;
+
+/// Replaces the remote file with stdin via a temp file and rename, keeping a
+/// timestamped copy of the old one. A symlinked file (as dotfile managers
+/// set up) is replaced at its target, so the link stays. Prints the backup
+/// path, if any.
+const WRITE_SCRIPT: &str = r#"umask 077
+d="$HOME/.ssh"
+f="$d/authorized_keys"
+[ -d "$d" ] || mkdir -m 700 "$d" || exit 1
+if [ -L "$f" ]; then f=$(readlink -f "$f") && [ -n "$f" ] || exit 1; fi
+tmp="$f.tmp.$$"
+cat > "$tmp" || { rm -f "$tmp"; exit 1; }
+if [ -f "$f" ]; then
+  b="$f.bak.$(date +%Y%m%d%H%M%S)"
+  cp -p "$f" "$b" || { rm -f "$tmp"; exit 1; }
+  echo "$b"
+fi
+mv -f "$tmp" "$f""#;
+
+/// One key line from `authorized_keys`, including commented-out ones.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorizedKey {
+    /// 1-based line number, used together with the fingerprint to address edits.
+    pub line: usize,
+    pub options: Vec<String>,
+    pub key_type: String,
+    pub fingerprint: Option<String>,
+    pub comment: String,
+    /// False for lines that were disabled by commenting them out.
+    pub enabled: bool,
+    /// Set when an active line could not be parsed as a key.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorizedKeysEdit {
+    pub backup_path: Option<String>,
+    pub entries: Vec<AuthorizedKey>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallKeyResult {
+    pub fingerprint: String,
+    /// The key was already authorized, so nothing was appended.
+    pub already_present: bool,
+    /// A fresh login using only this key succeeded.
+    pub verified: bool,
+    pub verify_error: Option<String>,
+}
+
+/// Adds a local public key to the remote user's `~/.ssh/authorized_keys`,
+/// like `ssh-copy-id`, then checks that a new connection can log in with it.
+///
+/// `key_path` may name the private key or its `.pub` file.
+pub async fn install_key(
+    connection: &SshConnection,
+    key_path: &Path,
+    passphrase: Option<String>,
+    unlocker: &KeyUnlocker,
+    app_handle: &tauri::AppHandle,
+    session_id: &str,
+) -> Result<InstallKeyResult> {
+    let info = keygen::inspect_key(key_path)?;
+    let line = info
+        .public_key
+        .ok_or_else(|| anyhow!("No public key found for {}", key_path.display()))?;
+    let public = PublicKey::from_openssh(&line)?;
+    let fingerprint = public.fingerprint(HashAlg::Sha256).to_string();
+
+    let output = remote::exec(connection, &install_script(&public)?, None).await?;
+    if !output.success() {
+        return Err(anyhow!(
+            "Failed to update authorized_keys: {}",
+            output.stderr.trim()
+        ));
+    }
+    let already_present = output.stdout.trim() == "present";
+
+    let private_key_path = private_key_path(key_path);
+    let verification = if private_key_path.exists() {
+        let metadata = connection.metadata().await;
+        let accepted_key = {
+            let session = connection.get_session().await;
+            let session = session.lock().await;
+            session
+                .as_ref()
+                .and_then(|session| session.host_key())
+                .map(|(key, _)| key.to_vec())
+        };
+        verify_login(
+            &metadata,
+            accepted_key,
+            &private_key_path,
+            passphrase,
+            unlocker,
+            app_handle,
+            session_id,
+        )
+        .await
+    } else {
+        Err(anyhow!(
+            "Private key not found, so the login was not verified"
+        ))
+    };
+
+    Ok(InstallKeyResult {
+        fingerprint,
+        already_present,
+        verified: verification.is_ok(),
+        verify_error: verification.err().map(|e| e.to_string()),
+    })
+}
+
+/// Reads and parses the remote user's `~/.ssh/authorized_keys`.
+pub async fn list(connection: &SshConnection) -> Result<Vec<AuthorizedKey>> {
+    Ok(parse_entries(&read_remote(connection).await?))
+}
+
+/// Appends a key, with optional `authorized_keys` options such as
+/// `from="10.0.0.0/8"` or `no-port-forwarding`.
+pub async fn add(
+    connection: &SshConnection,
+    public_key: &str,
+    options: &[String],
+) -> Result<AuthorizedKeysEdit> {
+    let public = PublicKey::from_openssh(public_key.trim())?;
+    let fingerprint = public.fingerprint(HashAlg::Sha256).to_string();
+
+    let mut line = public.to_openssh()?;
+    if !options.is_empty() {
+        line = format!("{} {}", options.join(","), line);
+    }
+    // Round-trip through the parser so malformed options are caught here
+    // rather than by sshd, which would ignore the whole line
+    let parsed = parse_line(&line, 0, true)
+        .filter(|entry| entry.fingerprint.as_deref() == Some(fingerprint.as_str()))
+        .ok_or_else(|| anyhow!("Invalid key options"))?;
+    if parsed.options.len() != options.len() {
+        return Err(anyhow!("Invalid key options"));
+    }
+
+    let contents = read_remote(connection).await?;
+    if let Some(existing) = parse_entries(&contents)
+        .iter()
+        .find(|entry| entry.enabled && entry.fingerprint.as_deref() == Some(fingerprint.as_str()))
+    {
+        return Err(anyhow!(
+            "This key is already authorized on line {}",
+            existing.line
+        ));
+    }
+
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    lines.push(line);
+    write_remote(connection, &lines).await
+}
+
+/// Comments out (or restores) the entry on `line`, which must still hold the
+/// key with `fingerprint`.
+pub async fn set_enabled(
+    connection: &SshConnection,
+    line: usize,
+    fingerprint: &str,
+    enabled: bool,
+) -> Result<AuthorizedKeysEdit> {
+    let contents = read_remote(connection).await?;
+    let entry = find_entry(&contents, line, fingerprint)?;
+    if entry.enabled == enabled {
+        return Ok(AuthorizedKeysEdit {
+            backup_path: None,
+            entries: parse_entries(&contents),
+        });
+    }
+
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    let text = &mut lines[line - 1];
+    *text = if enabled {
+        text.trim_start()
+            .trim_start_matches('#')
+            .trim_start()
+            .to_string()
+    } else {
+        format!("# {}", text)
+    };
+    write_remote(connection, &lines).await
+}
+
+/// Deletes the entry on `line`, which must still hold the key with `fingerprint`.
+pub async fn remove(
+    connection: &SshConnection,
+    line: usize,
+    fingerprint: &str,
+) -> Result<AuthorizedKeysEdit> {
+    let contents = read_remote(connection).await?;
+    find_entry(&contents, line, fingerprint)?;
+
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    lines.remove(line - 1);
+    write_remote(connection, &lines).await
+}
+
+fn find_entry(contents: &str, line: usize, fingerprint: &str) -> Result<AuthorizedKey> {
+    parse_entries(contents)
+        .into_iter()
+        .find(|entry| entry.line == line && entry.fingerprint.as_deref() == Some(fingerprint))
+        .ok_or_else(|| anyhow!("authorized_keys changed since it was read; reload and try again"))
+}
+
+async fn read_remote(connection: &SshConnection) -> Result<String> {
+    let output = remote::exec(connection, READ_SCRIPT, None).await?;
+    if !output.success() {
+        return Err(anyhow!(
+            "Failed to read authorized_keys: {}",
+            output.stderr.trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+async fn write_remote(connection: &SshConnection, lines: &[String]) -> Result<AuthorizedKeysEdit> {
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+
+    let output = remote::exec(
+        connection,
+        WRITE_SCRIPT,
+        Some(contents.clone().into_bytes()),
+    )
+    .await?;
+    if !output.success() {
+        return Err(anyhow!(
+            "Failed to write authorized_keys: {}",
+            output.stderr.trim()
+        ));
+    }
+
+    let backup_path = Some(output.stdout.trim().to_string()).filter(|p| !p.is_empty());
+    Ok(AuthorizedKeysEdit {
+        backup_path,
+        entries: parse_entries(&contents),
+    })
+}
+
+fn parse_entries(contents: &str) -> Vec<AuthorizedKey> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, text)| {
+            let trimmed = text.trim();
+            if trimmed.is_empty() {
+                return None;
+            }
+            match trimmed.strip_prefix('#') {
+                // Only comments that hold a key count as disabled entries
+                Some(rest) => parse_line(rest.trim(), index + 1, false)
+                    .filter(|entry| entry.fingerprint.is_some()),
+                None => parse_line(trimmed, index + 1, true),
+            }
+        })
+        .collect()
+}
+
+/// Parses `[options] keytype base64 [comment]`. Returns `None` only for
+/// disabled lines, which are ordinary comments unless they hold a key.
+fn parse_line(text: &str, line: usize, enabled: bool) -> Option<AuthorizedKey> {
+    let mut entry = AuthorizedKey {
+        line,
+        options: Vec::new(),
+        key_type: String::new(),
+        fingerprint: None,
+        comment: String::new(),
+        enabled,
+        error: None,
+    };
+
+    let mut rest = text;
+    if !is_key_type(first_token(rest)) {
+        let (options, remainder) = split_options(rest);
+        entry.options = options;
+        rest = remainder.trim_start();
+    }
+
+    let key_type = first_token(rest);
+    rest = rest[key_type.len()..].trim_start();
+    let blob = first_token(rest);
+    entry.key_type = key_type.to_string();
+    entry.comment = rest[blob.len()..].trim().to_string();
+
+    match PublicKey::from_openssh(&format!("{} {}", key_type, blob)) {
+        Ok(public) => entry.fingerprint = Some(public.fingerprint(HashAlg::Sha256).to_string()),
+        Err(_) if !enabled => return None,
+        Err(e) => entry.error = Some(format!("Not a valid key: {}", e)),
+    }
+    Some(entry)
+}
+
+fn first_token(text: &str) -> &str {
+    text.split_whitespace().next().unwrap_or("")
+}
+
+fn is_key_type(token: &str) -> bool {
+    token.starts_with("ssh-") || token.starts_with("ecdsa-sha2-") || token.starts_with("sk-")
+}
+
+/// Splits the leading option list off a line. Options are comma separated and
+/// may contain quoted values with spaces or commas, e.g. `command="a, b"`.
+fn split_options(text: &str) -> (Vec<String>, &str) {
+    let mut options = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quotes => {
+                current.push(c);
+                escaped = true;
+            }
+            '"' => {
+                current.push(c);
+                in_quotes = !in_quotes;
+            }
+            ',' if !in_quotes => options.push(std::mem::take(&mut current)),
+            c if c.is_whitespace() && !in_quotes => {
+                options.push(current);
+                return (options, &text[i..]);
+            }
+            c => current.push(c),
+        }
+    }
+
+    options.push(current);
+    (options, "")
+}
+
+/// Shell script that appends the key unless an active line already holds it.
+/// Only creates `~/.ssh` and the file when missing, so existing modes are kept.
+fn install_script(public: &PublicKey) -> Result<String> {
+    let line = public.to_openssh()?;
+    let blob = line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("Invalid public key"))?;
+
+    Ok(format!(
+        r#"umask 077
+d="$HOME/.ssh"
+f="$d/authorized_keys"
+[ -d "$d" ] || mkdir -m 700 "$d" || exit 1
+if [ -f "$f" ] && grep -v '^[[:space:]]*#' "$f" | grep -qF -- {blob}; then
+  echo present
+  exit 0
+fi
+if [ -s "$f" ] && [ -n "$(tail -c 1 "$f")" ]; then echo >> "$f" || exit 1; fi
+printf '%s\n' {line} >> "$f" && echo added"#,
+        blob = shell_quote(blob),
+        line = shell_quote(&line),
+    ))
+}
+
+fn private_key_path(key_path: &Path) -> PathBuf {
+    if key_path.extension().is_some_and(|ext| ext == "pub") {
+        key_path.with_extension("")
+    } else {
+        key_path.to_path_buf()
+    }
+}
+
+/// Opens a separate connection and authenticates with nothing but `key_path`.
+/// The server must present a host key known_hosts trusts, or the one the
+/// user accepted for the session the key was installed through.
+async fn verify_login(
+    metadata: &SessionMetadata,
+    accepted_key: Option<Vec<u8>>,
+    key_path: &Path,
+    passphrase: Option<String>,
+    unlocker: &KeyUnlocker,
+    app_handle: &tauri::AppHandle,
+    session_id: &str,
+) -> Result<()> {
+    let (host, port) = (metadata.host.clone(), metadata.port);
+    let session = tokio::task::spawn_blocking(move || -> Result<Session> {
+        let tcp = TcpStream::connect((host.as_str(), port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(VERIFY_TIMEOUT_MS);
+        session.handshake()?;
+
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| anyhow!("The server did not send a host key"))?;
+        let known = known_hosts::check(&known_hosts::default_path(), &host, port, key)
+            .is_ok_and(|status| status == HostKeyStatus::Known);
+        if !known && accepted_key.as_deref() != Some(key) {
+            return Err(anyhow!(
+                "The host key of {} is not the one the session was opened with",
+                known_hosts::host_name(&host, port)
+            ));
+        }
+        Ok(session)
+    })
+    .await??;
+
+    let options = AuthOptions {
+        methods: vec![AuthMethod::PublicKey],
+        identity_files: vec![key_path.display().to_string()],
+        passphrase,
+        ..Default::default()
+    };
+    let result = auth::authenticate(
+        &session,
+        &metadata.username,
+        &options,
+        unlocker,
+        app_handle,
+        session_id,
+        // The tab showed this host's banner when it connected
+        false,
+    )
+    .await;
+
+    tokio::task::spawn_blocking(move || {
+        let _ = session.disconnect(None, "Key verified", None);
+    });
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJiZEONYFMuyt6moMn6E0bG/hiFj/OC25kxqhdPy9JlJ";
+
+    #[test]
+    fn plain_lines_have_no_options() {
+        let entry = parse_line(&format!("{} laptop key", KEY), 1, true).unwrap();
+        assert!(entry.options.is_empty());
+        assert_eq!(entry.key_type, "ssh-ed25519");
+        assert_eq!(entry.comment, "laptop key");
+        assert!(entry.fingerprint.is_some());
+        assert!(entry.error.is_none());
+    }
+
+    #[test]
+    fn options_split_on_commas_outside_quotes() {
+        let line = format!(
+            r#"command="echo a, b",no-pty,from="10.0.0.0/8,192.168.1.1" {} ci"#,
+            KEY
+        );
+        let entry = parse_line(&line, 3, true).unwrap();
+        assert_eq!(
+            entry.options,
+            [
+                r#"command="echo a, b""#,
+                "no-pty",
+                r#"from="10.0.0.0/8,192.168.1.1""#
+            ]
+        );
+        assert_eq!(entry.key_type, "ssh-ed25519");
+        assert_eq!(entry.comment, "ci");
+        assert_eq!(entry.line, 3);
+    }
+
+    #[test]
+    fn escaped_quotes_stay_inside_the_option() {
+        let line = format!(r#"command="echo \"a b\", c",restrict {}"#, KEY);
+        let entry = parse_line(&line, 1, true).unwrap();
+        assert_eq!(entry.options, [r#"command="echo \"a b\", c""#, "restrict"]);
+        assert!(entry.fingerprint.is_some());
+    }
+
+    #[test]
+    fn broken_lines_are_reported_or_skipped() {
+        let entry = parse_line("ssh-ed25519 not-base64", 1, true).unwrap();
+        assert!(entry.fingerprint.is_none());
+        assert!(entry.error.is_some());
+        // A commented-out line that isn't a key is just a comment
+        assert!(parse_line("just a note", 1, false).is_none());
+    }
+}
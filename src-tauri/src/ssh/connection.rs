@@ -1,12 +1,43 @@
 use anyhow::{anyhow, Result};
+use serde::Serialize;
 use ssh2::{Channel, Session};
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use std::net::{TcpStream, ToSocketAddrs};
 use std::sync::Arc;
+use std::time::Instant;
 use tauri::Emitter;
 use tokio::sync::{mpsc, Mutex, watch};
 
+use super::auth::{self, AuthOptions, KeyUnlocker};
+use super::diagnostics::{ConnectError, ConnectionTrace};
+use super::known_hosts;
+use super::prompt::PromptBroker;
+use super::remote;
+use super::sftp::{self, OwnerNames, SftpChannel};
+use crate::metrics::{self, SessionMetrics};
+
+/// How often round-trip time is measured.
+const RTT_PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long opening the SFTP subsystem may take before giving up.
+const SFTP_OPEN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// libssh2's "would block" return code for non-blocking calls.
+pub(crate) const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// What the server told us about itself while connecting.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionMetadata {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    /// Identification string, e.g. `SSH-2.0-OpenSSH_9.6`.
+    pub server_id: Option<String>,
+    /// Pre-auth banner (`SSH_MSG_USERAUTH_BANNER`), usually a legal notice.
+    pub auth_banner: Option<String>,
+    pub connected_at: Option<i64>,
+}
+
 #[derive(Clone)]
 pub struct SshConnection {
     session_id: String,
@@ -15,6 +46,13 @@ pub struct SshConnection {
     tcp_stream: Arc<Mutex<Option<TcpStream>>>,
     shutdown_tx: Arc<Mutex<Option<watch::Sender<bool>>>>,
     input_tx: Arc<Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>,
+    metadata: Arc<Mutex<SessionMetadata>>,
+    trace: Arc<Mutex<Option<ConnectionTrace>>>,
+    metrics: Arc<SessionMetrics>,
+    /// SFTP channel shared by every file operation, opened on first use.
+    sftp: Arc<Mutex<Option<Arc<SftpChannel>>>>,
+    /// The host's user and group names, read on the first directory listing.
+    owner_names: Arc<Mutex<Option<Arc<OwnerNames>>>>,
 }
 
 impl SshConnection {
@@ -26,6 +64,11 @@ impl SshConnection {
             tcp_stream: Arc::new(Mutex::new(None)),
             shutdown_tx: Arc::new(Mutex::new(None)),
             input_tx: Arc::new(Mutex::new(None)),
+            metadata: Arc::new(Mutex::new(SessionMetadata::default())),
+            trace: Arc::new(Mutex::new(None)),
+            metrics: Arc::new(SessionMetrics::default()),
+            sftp: Arc::new(Mutex::new(None)),
+            owner_names: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -34,49 +77,104 @@ impl SshConnection {
         host: String,
         port: u16,
         username: String,
-        private_key_path: Option<String>,
-        passphrase: Option<String>,
+        mut auth: AuthOptions,
+        unlocker: KeyUnlocker,
+        prompts: PromptBroker,
         app_handle: tauri::AppHandle,
     ) -> Result<()> {
-        let addr = format!("{}:{}", host, port);
-        let tcp = TcpStream::connect(&addr)?;
-        tcp.set_nodelay(true)?;
-
-        let mut session = Session::new()?;
-        session.set_tcp_stream(tcp.try_clone()?);
-        session.handshake()?;
-
-        // Authenticate with private key (in blocking mode)
-        if let Some(key_path_str) = private_key_path {
-            let key_path = Path::new(&key_path_str);
-            if let Some(pass) = passphrase.as_ref() {
-                session.userauth_pubkey_file(&username, None, key_path, Some(pass))?;
-            } else {
-                session.userauth_pubkey_file(&username, None, key_path, None)?;
-            }
+        let mut trace = ConnectionTrace::new(&host, port, &username);
+
+        let opened: Result<(Session, TcpStream, Channel)> = async {
+            // Fetch secrets from password manager CLIs before touching the network
+            let secret_commands = auth.secret_commands.clone();
+            secret_commands.resolve_into(&mut auth).await?;
+
+            let tcp = connect_tcp(&host, port, &mut trace)?;
+            tcp.set_nodelay(true)?;
+
+            let mut session = Session::new()?;
+            session.set_tcp_stream(tcp.try_clone()?);
+            let started = Instant::now();
+            let handshake = session.handshake();
+            trace.handshake_ms = Some(trace.record("handshake", started, &handshake));
+            handshake?;
+            trace.record_handshake(&session);
+
+            let started = Instant::now();
+            let host_key = known_hosts::verify_session(
+                &session,
+                &host,
+                port,
+                &prompts,
+                &app_handle,
+                &self.session_id,
+            )
+            .await;
+            trace.record("host-key", started, &host_key);
+            trace.host_key_status = Some(host_key?);
+
+            // Authenticate (in blocking mode), falling back through the configured methods
+            let started = Instant::now();
+            let auth_result = auth::authenticate(
+                &session,
+                &username,
+                &auth,
+                &unlocker,
+                &app_handle,
+                &self.session_id,
+                true,
+            )
+            .await;
+            // Individual attempts are listed separately, so the step itself has no detail
+            trace.step("auth", started, auth_result.is_ok(), None);
+            trace.record_auth(&auth_result);
+
+            // `authenticate` has already sent `ssh-banner`, before its first prompt.
+            // Keep the banner even if authentication failed, since that is when a
+            // legal notice matters most.
+            let server_id = session.banner().map(|s| s.to_string());
+            let auth_banner = session.userauth_banner().ok().flatten().map(|s| s.to_string());
+            *self.metadata.lock().await = SessionMetadata {
+                host: host.clone(),
+                port,
+                username: username.clone(),
+                server_id,
+                auth_banner,
+                connected_at: None,
+            };
+
+            auth_result?;
+
+            // Request PTY and start shell
+            let started = Instant::now();
+            let mut channel = session.channel_session()?;
+            channel.request_pty("xterm-256color", None, Some((80, 24, 0, 0)))?;
+
+            // Set UTF-8 locale environment variables for proper Unicode/Cyrillic support
+            let _ = channel.setenv("LANG", "en_US.UTF-8");
+            let _ = channel.setenv("LC_ALL", "en_US.UTF-8");
+
+            let shell = channel.shell();
+            trace.record("shell", started, &shell);
+            shell?;
+            channel.handle_extended_data(ssh2::ExtendedData::Merge)?;
+
+            Ok((session, tcp, channel))
         }
-        // If no key path is provided, authentication will be attempted without it.
-        // This relies on the server allowing other auth methods (e.g., none, password).
-
-        if !session.authenticated() {
-            return Err(anyhow!("Authentication failed"));
-        }
-
-        // Request PTY and start shell
-        let mut channel = session.channel_session()?;
-        channel.request_pty("xterm-256color", None, Some((80, 24, 0, 0)))?;
+        .await;
 
-        // Set UTF-8 locale environment variables for proper Unicode/Cyrillic support
-        let _ = channel.setenv("LANG", "en_US.UTF-8");
-        let _ = channel.setenv("LC_ALL", "en_US.UTF-8");
-
-        channel.shell()?;
-        channel.handle_extended_data(ssh2::ExtendedData::Merge)?;
+        trace.finish(&opened);
+        *self.trace.lock().await = Some(trace.clone());
+        let (session, tcp, channel) = opened.map_err(|e| ConnectError {
+            message: e.to_string(),
+            trace,
+        })?;
 
         // NOW set session to non-blocking mode for I/O operations
         session.set_blocking(false);
 
         // Store session, tcp stream and channel
+        self.metadata.lock().await.connected_at = Some(chrono::Utc::now().timestamp());
         *self.session.lock().await = Some(session);
         *self.tcp_stream.lock().await = Some(tcp);
         *self.channel.lock().await = Some(channel);
@@ -93,6 +191,19 @@ impl SshConnection {
         // This prevents lock contention and SSH protocol corruption
         let session_id = self.session_id.clone();
         let channel_arc = Arc::clone(&self.channel);
+        let io_metrics = Arc::clone(&self.metrics);
+
+        metrics::spawn_reporter(
+            app_handle.clone(),
+            session_id.clone(),
+            Arc::clone(&self.metrics),
+            shutdown_rx.clone(),
+        );
+        spawn_rtt_probe(
+            Arc::clone(&self.sftp),
+            Arc::clone(&self.metrics),
+            shutdown_rx.clone(),
+        );
 
         tokio::task::spawn_blocking(move || {
             let mut read_buffer = [0u8; 8192];
@@ -120,6 +231,7 @@ impl SshConnection {
                         if write_pos < data.len() {
                             match channel.write(&data[write_pos..]) {
                                 Ok(n) => {
+                                    io_metrics.record_write(n);
                                     write_pos += n;
                                     if write_pos >= data.len() {
                                         // Finished writing this buffer
@@ -155,6 +267,7 @@ impl SshConnection {
                         }
                         Ok(n) => {
                             // Successfully read data
+                            io_metrics.record_read(n);
                             let data = String::from_utf8_lossy(&read_buffer[..n]).to_string();
                             let _ = app_handle.emit(&format!("ssh-output:{}", session_id), data);
                         }
@@ -223,6 +336,9 @@ impl SshConnection {
             let _ = channel.wait_close();
         }
 
+        // Close the SFTP channel before the session it runs on
+        *self.sftp.lock().await = None;
+
         // Disconnect session
         if let Some(ref mut session) = *self.session.lock().await {
             let _ = session.disconnect(None, "Client disconnecting", None);
@@ -240,4 +356,132 @@ impl SshConnection {
     pub async fn get_session(&self) -> Arc<Mutex<Option<Session>>> {
         self.session.clone()
     }
+
+    /// The connection's SFTP channel, opened on first use and then reused.
+    pub async fn sftp(&self) -> Result<Arc<SftpChannel>> {
+        let mut sftp = self.sftp.lock().await;
+        if let Some(ref handle) = *sftp {
+            return Ok(Arc::clone(handle));
+        }
+
+        let session = self
+            .session
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| anyhow!("No active session"))?;
+        let handle = tokio::task::spawn_blocking(move || {
+            remote::retry(Instant::now() + SFTP_OPEN_TIMEOUT, || session.sftp())
+        })
+        .await??;
+
+        let handle = Arc::new(SftpChannel::new(handle));
+        *sftp = Some(Arc::clone(&handle));
+        Ok(handle)
+    }
+
+    /// Drops the SFTP channel so the next operation opens a fresh one.
+    pub async fn reset_sftp(&self) {
+        *self.sftp.lock().await = None;
+    }
+
+    pub async fn owner_names(&self) -> Option<Arc<OwnerNames>> {
+        self.owner_names.lock().await.clone()
+    }
+
+    pub async fn set_owner_names(&self, names: Arc<OwnerNames>) {
+        *self.owner_names.lock().await = Some(names);
+    }
+
+    pub async fn metadata(&self) -> SessionMetadata {
+        self.metadata.lock().await.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<SessionMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Trace of the last `connect` call, whether or not it succeeded.
+    pub async fn trace(&self) -> Option<ConnectionTrace> {
+        self.trace.lock().await.clone()
+    }
+}
+
+/// Measures round-trip time with an SFTP `realpath` on the connection's
+/// channel, which the server's SFTP process answers without starting
+/// anything. Sessions that never opened SFTP get no samples; probing stops
+/// for good after a failed request.
+fn spawn_rtt_probe(
+    sftp: Arc<Mutex<Option<Arc<SftpChannel>>>>,
+    metrics: Arc<SessionMetrics>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RTT_PROBE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.changed() => break,
+            }
+            if *shutdown_rx.borrow() {
+                break;
+            }
+
+            let Some(channel) = sftp.lock().await.clone() else {
+                continue;
+            };
+            let probe = Arc::clone(&channel);
+            match tokio::task::spawn_blocking(move || sftp::measure_rtt(&probe)).await {
+                Ok(Some(Ok(rtt))) => metrics.record_rtt(rtt),
+                // The channel was busy with another request
+                Ok(None) => {}
+                Ok(Some(Err(_))) => {
+                    // A broken channel, or one whose timed-out request may
+                    // still be answered, is reopened by its next user
+                    let mut current = sftp.lock().await;
+                    if current.as_ref().is_some_and(|c| Arc::ptr_eq(c, &channel)) {
+                        *current = None;
+                    }
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Resolves `host` and tries each address in turn, recording every step.
+fn connect_tcp(host: &str, port: u16, trace: &mut ConnectionTrace) -> Result<TcpStream> {
+    let started = Instant::now();
+    let resolved = (host, port).to_socket_addrs();
+    trace.record("dns", started, &resolved);
+    let addrs: Vec<_> = resolved
+        .map_err(|e| anyhow!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    trace.resolved_addresses = addrs.iter().map(|a| a.to_string()).collect();
+    if let Some(step) = trace.steps.last_mut() {
+        step.detail = Some(trace.resolved_addresses.join(", "));
+    }
+
+    let mut last_error = None;
+    for addr in addrs {
+        let started = Instant::now();
+        match TcpStream::connect(addr) {
+            Ok(tcp) => {
+                let elapsed = trace.step("tcp-connect", started, true, Some(addr.to_string()));
+                trace.connected_address = Some(addr.to_string());
+                trace.tcp_connect_ms = Some(elapsed);
+                return Ok(tcp);
+            }
+            Err(e) => {
+                trace.step("tcp-connect", started, false, Some(format!("{}: {}", addr, e)));
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => anyhow!("Failed to connect to {}:{}: {}", host, port, e),
+        None => anyhow!("{} did not resolve to any address", host),
+    })
 }
@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+use super::auth::AuthOptions;
+use super::totp::KEYRING_SERVICE;
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Keep error messages readable when a CLI dumps a wall of text on stderr.
+const MAX_STDERR_CHARS: usize = 300;
+
+/// Local commands whose stdout supplies a secret at connect time, e.g.
+/// `pass show servers/db1`, `op read op://Ops/db1/password` or
+/// `bw get password db1`. Only the commands are stored in the host profile;
+/// their output is used for the one connection and then dropped.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SecretCommands {
+    pub password_command: Option<String>,
+    pub passphrase_command: Option<String>,
+    /// Seconds to wait for each command. Defaults to 30, which leaves time
+    /// for an interactive unlock (Touch ID, `op signin`).
+    pub timeout_secs: Option<u64>,
+}
+
+impl SecretCommands {
+    /// Runs the configured commands and fills the matching fields of `auth`.
+    /// Values already present in `auth` are left alone.
+    pub async fn resolve_into(&self, auth: &mut AuthOptions) -> Result<()> {
+        let timeout = Duration::from_secs(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+
+        if auth.password.is_none() {
+            if let Some(ref command) = self.password_command {
+                auth.password = Some(run_secret_command("password", command, timeout).await?);
+            }
+        }
+        if auth.passphrase.is_none() {
+            if let Some(ref command) = self.passphrase_command {
+                auth.passphrase = Some(run_secret_command("passphrase", command, timeout).await?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs `command` through the user's shell and returns its first line of
+/// output. The secret itself never appears in returned errors.
+pub async fn run_secret_command(field: &str, command: &str, timeout: Duration) -> Result<String> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+
+    let child = Command::new(shell)
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run {} command: {}", field, e))?;
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "{} command timed out after {}s",
+                capitalize(field),
+                timeout.as_secs()
+            )
+        })?
+        .map_err(|e| anyhow!("Failed to run {} command: {}", field, e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr: String = stderr.trim().chars().take(MAX_STDERR_CHARS).collect();
+        let status = match output.status.code() {
+            Some(code) => format!("exit code {}", code),
+            None => "a signal".to_string(),
+        };
+        return Err(if stderr.is_empty() {
+            anyhow!("{} command failed with {}", capitalize(field), status)
+        } else {
+            anyhow!(
+                "{} command failed with {}: {}",
+                capitalize(field),
+                status,
+                stderr
+            )
+        });
+    }
+
+    // `pass` and friends put the secret on the first line and metadata after it
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("{} command printed non-UTF-8 output", capitalize(field)))?;
+    let secret = stdout.lines().next().unwrap_or("").trim_end_matches('\r');
+    if secret.is_empty() {
+        return Err(anyhow!("{} command printed nothing", capitalize(field)));
+    }
+    Ok(secret.to_string())
+}
+
+fn passphrase_entry(key_path: &Path) -> Result<keyring::Entry> {
+    Ok(keyring::Entry::new(
+        KEYRING_SERVICE,
+        &format!("passphrase:{}", key_path.display()),
+    )?)
+}
+
+/// Stores a key's passphrase in the system keyring, so the agent can load
+/// the key again without asking.
+pub fn save_key_passphrase(key_path: &Path, passphrase: &str) -> Result<()> {
+    passphrase_entry(key_path)?.set_password(passphrase)?;
+    Ok(())
+}
+
+pub fn saved_key_passphrase(key_path: &Path) -> Result<Option<String>> {
+    match passphrase_entry(key_path)?.get_password() {
+        Ok(passphrase) => Ok(Some(passphrase)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn forget_key_passphrase(key_path: &Path) -> Result<()> {
+    match passphrase_entry(key_path)?.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn capitalize(field: &str) -> String {
+    let mut chars = field.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,205 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use serde::Serialize;
+use ssh2::{HashType, MethodType, Session};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+use super::auth::{AuthAttempt, AuthFailure};
+use super::known_hosts::HostKeyStatus;
+
+/// One timed step of a connection attempt.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+    pub name: String,
+    pub elapsed_ms: u64,
+    pub ok: bool,
+    pub detail: Option<String>,
+}
+
+/// Algorithms agreed on during key exchange, client-to-server (`_cs`) and
+/// server-to-client (`_sc`).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NegotiatedAlgorithms {
+    pub kex: Option<String>,
+    pub host_key: Option<String>,
+    pub cipher_cs: Option<String>,
+    pub cipher_sc: Option<String>,
+    pub mac_cs: Option<String>,
+    pub mac_sc: Option<String>,
+    pub compression_cs: Option<String>,
+    pub compression_sc: Option<String>,
+}
+
+impl NegotiatedAlgorithms {
+    fn from_session(session: &Session) -> Self {
+        let method = |kind| session.methods(kind).map(|s| s.to_string());
+        Self {
+            kex: method(MethodType::Kex),
+            host_key: method(MethodType::HostKey),
+            cipher_cs: method(MethodType::CryptCs),
+            cipher_sc: method(MethodType::CryptSc),
+            mac_cs: method(MethodType::MacCs),
+            mac_sc: method(MethodType::MacSc),
+            compression_cs: method(MethodType::CompCs),
+            compression_sc: method(MethodType::CompSc),
+        }
+    }
+}
+
+/// Step-by-step record of a connection attempt, kept for both failed and
+/// successful sessions so users can send something actionable.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConnectionTrace {
+    pub target: String,
+    pub started_at: i64,
+    pub steps: Vec<TraceStep>,
+    pub resolved_addresses: Vec<String>,
+    pub connected_address: Option<String>,
+    pub tcp_connect_ms: Option<u64>,
+    pub handshake_ms: Option<u64>,
+    pub algorithms: Option<NegotiatedAlgorithms>,
+    /// `SHA256:` fingerprint of the server's host key, as `ssh` prints it.
+    pub host_key_fingerprint: Option<String>,
+    /// How the host key compared with `known_hosts`; `unknown` means it was
+    /// seen for the first time and has now been added.
+    pub host_key_status: Option<HostKeyStatus>,
+    pub auth_attempts: Vec<AuthAttempt>,
+    /// Methods the server offered, when authentication failed.
+    pub auth_server_methods: Vec<String>,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+impl ConnectionTrace {
+    pub fn new(host: &str, port: u16, username: &str) -> Self {
+        Self {
+            target: format!("{}@{}:{}", username, host, port),
+            started_at: chrono::Utc::now().timestamp_millis(),
+            ..Default::default()
+        }
+    }
+
+    /// Appends a step that started at `started` and ended now.
+    pub fn step(&mut self, name: &str, started: Instant, ok: bool, detail: Option<String>) -> u64 {
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        self.steps.push(TraceStep {
+            name: name.to_string(),
+            elapsed_ms,
+            ok,
+            detail,
+        });
+        elapsed_ms
+    }
+
+    /// Like `step`, taking success and the error text from `result`.
+    pub fn record<T, E: fmt::Display>(
+        &mut self,
+        name: &str,
+        started: Instant,
+        result: &Result<T, E>,
+    ) -> u64 {
+        match result {
+            Ok(_) => self.step(name, started, true, None),
+            Err(e) => self.step(name, started, false, Some(e.to_string())),
+        }
+    }
+
+    /// Captures what key exchange settled on. Call right after the handshake.
+    pub fn record_handshake(&mut self, session: &Session) {
+        self.algorithms = Some(NegotiatedAlgorithms::from_session(session));
+        self.host_key_fingerprint = session
+            .host_key_hash(HashType::Sha256)
+            .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)));
+    }
+
+    /// Stores the auth attempts from either outcome of `auth::authenticate`,
+    /// and on failure the methods the server offered.
+    pub fn record_auth(&mut self, result: &anyhow::Result<Vec<AuthAttempt>>) {
+        match result {
+            Ok(attempts) => self.auth_attempts = attempts.clone(),
+            Err(e) => {
+                if let Some(failure) = e.downcast_ref::<AuthFailure>() {
+                    self.auth_attempts = failure.attempts.clone();
+                    self.auth_server_methods = failure.server_methods.clone();
+                }
+            }
+        }
+    }
+
+    pub fn finish<T>(&mut self, result: &anyhow::Result<T>) {
+        self.succeeded = result.is_ok();
+        self.error = result.as_ref().err().map(|e| e.to_string());
+    }
+
+    /// Plain-text rendering for error messages and bug reports.
+    pub fn summary(&self) -> String {
+        let mut lines = vec![format!("Connection trace for {}", self.target)];
+        for step in &self.steps {
+            let status = if step.ok { "ok" } else { "FAILED" };
+            let mut line = format!("  {:<14} {:>6} ms  {}", step.name, step.elapsed_ms, status);
+            if let Some(ref detail) = step.detail {
+                line.push_str(&format!("  {}", detail));
+            }
+            lines.push(line);
+        }
+        if let Some(ref algorithms) = self.algorithms {
+            let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "?".to_string());
+            lines.push(format!(
+                "  kex {}, host key {}, cipher {}/{}, mac {}/{}",
+                show(&algorithms.kex),
+                show(&algorithms.host_key),
+                show(&algorithms.cipher_cs),
+                show(&algorithms.cipher_sc),
+                show(&algorithms.mac_cs),
+                show(&algorithms.mac_sc),
+            ));
+        }
+        if let Some(ref fingerprint) = self.host_key_fingerprint {
+            let status = match self.host_key_status {
+                Some(HostKeyStatus::Known) => " (known)",
+                Some(HostKeyStatus::Unknown) => " (new, added to known_hosts)",
+                _ => "",
+            };
+            lines.push(format!("  host key fingerprint {}{}", fingerprint, status));
+        }
+        for attempt in &self.auth_attempts {
+            lines.push(format!("  auth {}", attempt));
+        }
+        if !self.auth_server_methods.is_empty() {
+            lines.push(format!(
+                "  server accepts {}",
+                self.auth_server_methods.join(", ")
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// How many traces of failed connects are kept. Their session ids never
+/// reach a connection, so nothing else would remove them.
+const MAX_FAILED_TRACES: usize = 20;
+
+/// Drops the oldest traces of failed connects beyond `MAX_FAILED_TRACES`.
+pub fn prune_failed_traces(traces: &mut HashMap<String, ConnectionTrace>) {
+    let mut failed: Vec<(i64, String)> = traces
+        .iter()
+        .filter(|(_, trace)| !trace.succeeded)
+        .map(|(id, trace)| (trace.started_at, id.clone()))
+        .collect();
+    if failed.len() <= MAX_FAILED_TRACES {
+        return;
+    }
+    failed.sort();
+    for (_, id) in &failed[..failed.len() - MAX_FAILED_TRACES] {
+        traces.remove(id);
+    }
+}
+
+/// A failed connection, carrying the trace of how far it got.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}\n\n{}", trace.summary())]
+pub struct ConnectError {
+    pub message: String,
+    pub trace: ConnectionTrace,
+}
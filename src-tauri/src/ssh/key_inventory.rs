@@ -0,0 +1,267 @@
+//! Finds the SSH keys already on disk, so the key list doesn't have to be
+//! filled in by hand, and points out the ones `ssh` would refuse or that are
+//! too weak to keep using.
+
+use anyhow::Result;
+use serde::Serialize;
+use ssh_key::certificate::CertType;
+use ssh_key::{Certificate, HashAlg};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::auth::expand_tilde;
+use super::keygen::{self, KeyFormat, KeyInfo};
+
+/// Anything bigger is not a key, so it isn't worth reading.
+const MAX_KEY_FILE_SIZE: u64 = 64 * 1024;
+
+const MIN_RSA_BITS: u32 = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyIssue {
+    /// Group or others can access the private key; ssh refuses to use it.
+    InsecurePermissions,
+    /// DSA is disabled in current OpenSSH releases.
+    DsaKey,
+    WeakRsaKey,
+    MissingPublicKey,
+    /// The `.pub` file holds a different key than the private key.
+    PublicKeyMismatch,
+    CertificateExpired,
+    /// The certificate was issued for a different key.
+    CertificateMismatch,
+    /// A directory to scan could not be read; the entry has no key, and
+    /// `error` says why.
+    UnreadableDirectory,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub key_id: String,
+    /// `user` or `host`.
+    pub cert_type: String,
+    pub principals: Vec<String>,
+    /// Unix timestamps; `valid_before` is `None` for certificates that never expire.
+    pub valid_after: u64,
+    pub valid_before: Option<u64>,
+    pub ca_fingerprint: String,
+    pub expired: bool,
+}
+
+/// A private key with the `.pub` and `-cert.pub` files next to it, or a
+/// public key or certificate found on its own.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InventoryEntry {
+    pub private_key_path: Option<String>,
+    pub public_key_path: Option<String>,
+    pub certificate_path: Option<String>,
+    /// Details of the private key, or of the public key when there is none.
+    pub info: Option<KeyInfo>,
+    pub certificate: Option<CertificateInfo>,
+    pub issues: Vec<KeyIssue>,
+    /// Set when a file looks like a private key but could not be parsed, or
+    /// a directory could not be read.
+    pub error: Option<String>,
+}
+
+/// Scans `~/.ssh` and `extra_dirs` (not recursively) for keys.
+pub fn scan(extra_dirs: &[String]) -> Result<Vec<InventoryEntry>> {
+    let mut dirs = vec![expand_tilde("~/.ssh")];
+    for dir in extra_dirs {
+        let dir = expand_tilde(dir);
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+
+    let mut entries = Vec::new();
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        match scan_dir(dir) {
+            Ok(found) => entries.extend(found),
+            Err(e) => entries.push(InventoryEntry {
+                issues: vec![KeyIssue::UnreadableDirectory],
+                error: Some(format!("Failed to read {}: {}", dir.display(), e)),
+                ..Default::default()
+            }),
+        }
+    }
+    Ok(entries)
+}
+
+fn scan_dir(dir: &Path) -> Result<Vec<InventoryEntry>> {
+    // Sorted so the result is stable between scans
+    let mut files = BTreeMap::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let is_small_file =
+            fs::metadata(&path).is_ok_and(|meta| meta.is_file() && meta.len() <= MAX_KEY_FILE_SIZE);
+        if is_small_file {
+            files.insert(path.display().to_string(), path);
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut claimed = Vec::new();
+
+    for path in files.values() {
+        let name = path.display().to_string();
+        if name.ends_with(".pub") {
+            continue;
+        }
+        let Some(mut entry) = private_key_entry(path) else {
+            continue;
+        };
+
+        let public_path = PathBuf::from(format!("{}.pub", name));
+        let cert_path = PathBuf::from(format!("{}-cert.pub", name));
+        if files.contains_key(&public_path.display().to_string()) {
+            attach_public_key(&mut entry, &public_path);
+            claimed.push(public_path);
+        } else {
+            entry.issues.push(KeyIssue::MissingPublicKey);
+        }
+        if files.contains_key(&cert_path.display().to_string()) {
+            attach_certificate(&mut entry, &cert_path);
+            claimed.push(cert_path);
+        }
+        entries.push(entry);
+    }
+
+    // Public keys and certificates whose private key lives elsewhere
+    for path in files.values().filter(|path| !claimed.contains(path)) {
+        let name = path.display().to_string();
+        if name.ends_with("-cert.pub") {
+            let mut entry = InventoryEntry::default();
+            attach_certificate(&mut entry, path);
+            if entry.certificate.is_some() {
+                entries.push(entry);
+            }
+        } else if name.ends_with(".pub") {
+            if let Ok(info) = keygen::inspect_key(path) {
+                entries.push(InventoryEntry {
+                    public_key_path: Some(name),
+                    info: Some(info),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Parses `path` as a private key. Returns `None` for files that aren't keys
+/// at all, such as `config` or `known_hosts`.
+fn private_key_entry(path: &Path) -> Option<InventoryEntry> {
+    let mut entry = InventoryEntry {
+        private_key_path: Some(path.display().to_string()),
+        ..Default::default()
+    };
+
+    match keygen::inspect_key(path) {
+        Ok(info) if info.format == KeyFormat::PublicKey => return None,
+        Ok(info) => {
+            if info.algorithm == "dsa" {
+                entry.issues.push(KeyIssue::DsaKey);
+            }
+            if info.algorithm == "rsa" && info.bits.is_some_and(|bits| bits < MIN_RSA_BITS) {
+                entry.issues.push(KeyIssue::WeakRsaKey);
+            }
+            entry.info = Some(info);
+        }
+        Err(e) => {
+            let contents = fs::read_to_string(path).ok()?;
+            let looks_like_key = contents.contains("PRIVATE KEY-----")
+                || contents.starts_with("PuTTY-User-Key-File-");
+            if !looks_like_key {
+                return None;
+            }
+            entry.error = Some(e.to_string());
+        }
+    }
+
+    if has_insecure_permissions(path) {
+        entry.issues.insert(0, KeyIssue::InsecurePermissions);
+    }
+    Some(entry)
+}
+
+fn attach_public_key(entry: &mut InventoryEntry, path: &Path) {
+    entry.public_key_path = Some(path.display().to_string());
+    let Ok(public) = keygen::inspect_key(path) else {
+        return;
+    };
+
+    match entry.info {
+        // Encrypted PEM keys hide their public half, so their details were
+        // taken from this very file and there is nothing to compare
+        Some(ref info)
+            if !info.encrypted || matches!(info.format, KeyFormat::Openssh | KeyFormat::Ppk) =>
+        {
+            if info.fingerprint_sha256 != public.fingerprint_sha256 {
+                entry.issues.push(KeyIssue::PublicKeyMismatch);
+            }
+        }
+        Some(_) => {}
+        None => entry.info = Some(public),
+    }
+}
+
+fn attach_certificate(entry: &mut InventoryEntry, path: &Path) {
+    entry.certificate_path = Some(path.display().to_string());
+    let Some(certificate) = fs::read_to_string(path)
+        .ok()
+        .and_then(|text| Certificate::from_openssh(text.trim()).ok())
+    else {
+        return;
+    };
+
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    let valid_before = Some(certificate.valid_before()).filter(|&t| t != u64::MAX);
+    let expired = valid_before.is_some_and(|t| t <= now);
+    if expired {
+        entry.issues.push(KeyIssue::CertificateExpired);
+    }
+
+    let certified = certificate
+        .public_key()
+        .fingerprint(HashAlg::Sha256)
+        .to_string();
+    let key_fingerprint = entry
+        .info
+        .as_ref()
+        .and_then(|info| info.fingerprint_sha256.as_ref());
+    if key_fingerprint.is_some_and(|fingerprint| *fingerprint != certified) {
+        entry.issues.push(KeyIssue::CertificateMismatch);
+    }
+
+    entry.certificate = Some(CertificateInfo {
+        key_id: certificate.key_id().to_string(),
+        cert_type: match certificate.cert_type() {
+            CertType::User => "user",
+            CertType::Host => "host",
+        }
+        .to_string(),
+        principals: certificate.valid_principals().to_vec(),
+        valid_after: certificate.valid_after(),
+        valid_before,
+        ca_fingerprint: certificate
+            .signature_key()
+            .fingerprint(HashAlg::Sha256)
+            .to_string(),
+        expired,
+    });
+}
+
+#[cfg(unix)]
+fn has_insecure_permissions(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o077 != 0)
+}
+
+#[cfg(not(unix))]
+fn has_insecure_permissions(_path: &Path) -> bool {
+    false
+}
@@ -1,8 +1,18 @@
 use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use serde::{Deserialize, Serialize};
+use ssh_key::private::{EcdsaKeypair, Ed25519Keypair, KeypairData, RsaKeypair};
+use ssh_key::public::KeyData;
+use ssh_key::rand_core::{OsRng, RngCore};
+use ssh_key::{EcdsaCurve, HashAlg, LineEnding, Mpint, PrivateKey, PublicKey};
+use std::collections::HashMap;
 use std::fs;
-use std::io::Read;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+
+use super::ppk;
 
 pub fn get_key_type(key_path: &str) -> Result<String> {
     let path = Path::new(key_path);
@@ -10,68 +20,862 @@ pub fn get_key_type(key_path: &str) -> Result<String> {
         return Err(anyhow!("Key file does not exist"));
     }
 
-    let mut file = fs::File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+    Ok(inspect_key(path)
+        .map(|info| info.algorithm)
+        .unwrap_or_else(|_| "unknown".to_string()))
+}
+
+/// On-disk encoding of a key file.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyFormat {
+    Openssh,
+    PemPkcs1,
+    PemSec1,
+    PemDsa,
+    Pkcs8,
+    Ppk,
+    PublicKey,
+}
+
+/// Everything the key manager shows about a key file.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyInfo {
+    pub path: String,
+    pub format: KeyFormat,
+    /// Short family name: ed25519, ecdsa, rsa, dsa, ed25519-sk, ecdsa-sk or unknown.
+    pub algorithm: String,
+    /// Wire name such as `ssh-ed25519` or `ecdsa-sha2-nistp256`.
+    pub key_type: Option<String>,
+    pub bits: Option<u32>,
+    pub curve: Option<String>,
+    pub fingerprint_sha256: Option<String>,
+    pub fingerprint_md5: Option<String>,
+    pub comment: Option<String>,
+    pub encrypted: bool,
+    pub randomart: Option<String>,
+    pub public_key: Option<String>,
+    /// The `.pub` file next to the key, when one was found and parsed.
+    pub public_key_file: Option<String>,
+}
+
+/// Parses a private key (OpenSSH, PEM or PKCS#8) or a public key line.
+///
+/// Encrypted PEM and PKCS#8 keys hide their public half, so the details then
+/// come from the `.pub` file next to the key if there is one.
+pub fn inspect_key(key_path: &Path) -> Result<KeyInfo> {
+    let contents = fs::read_to_string(key_path)?;
+    let (sidecar_path, sidecar) = read_sidecar(key_path);
+
+    let parsed = parse_key(contents.trim())?;
+    let public = parsed.public.or_else(|| sidecar.clone());
+
+    let mut info = KeyInfo {
+        path: key_path.display().to_string(),
+        format: parsed.format,
+        algorithm: parsed.family.to_string(),
+        key_type: None,
+        bits: None,
+        curve: None,
+        fingerprint_sha256: None,
+        fingerprint_md5: None,
+        comment: None,
+        encrypted: parsed.encrypted,
+        randomart: None,
+        public_key: None,
+        public_key_file: sidecar
+            .is_some()
+            .then(|| sidecar_path.display().to_string()),
+    };
+
+    if let Some(public) = public {
+        let data = public.key_data();
+        let fingerprint = public.fingerprint(HashAlg::Sha256);
+        let bits = key_bits(data);
+
+        info.algorithm = key_family(data).to_string();
+        info.key_type = Some(data.algorithm().as_str().to_string());
+        info.bits = bits;
+        info.curve = key_curve(data).map(str::to_string);
+        info.fingerprint_sha256 = Some(fingerprint.to_string());
+        info.fingerprint_md5 = Some(md5_fingerprint(&public)?);
+        info.randomart = Some(fingerprint.to_randomart(&randomart_header(data, bits)));
+        info.public_key = public.to_openssh().ok();
+
+        // Encrypted OpenSSH keys keep the comment inside the encrypted section
+        info.comment = Some(public.comment())
+            .filter(|c| !c.is_empty())
+            .or_else(|| {
+                sidecar
+                    .as_ref()
+                    .map(|s| s.comment())
+                    .filter(|c| !c.is_empty())
+            })
+            .map(str::to_string);
+    }
+
+    Ok(info)
+}
+
+struct ParsedKey {
+    format: KeyFormat,
+    encrypted: bool,
+    /// Used when the public half can't be recovered from the file itself.
+    family: &'static str,
+    public: Option<PublicKey>,
+}
+
+impl ParsedKey {
+    fn encrypted(format: KeyFormat, family: &'static str) -> Self {
+        Self {
+            format,
+            encrypted: true,
+            family,
+            public: None,
+        }
+    }
+
+    fn plain(format: KeyFormat, data: KeyData) -> Self {
+        Self {
+            format,
+            encrypted: false,
+            family: key_family(&data),
+            public: Some(PublicKey::new(data, "")),
+        }
+    }
+}
+
+/// Finds and parses the `.pub` file that belongs to a private key.
+fn read_sidecar(key_path: &Path) -> (PathBuf, Option<PublicKey>) {
+    let sidecar_path = PathBuf::from(format!("{}.pub", key_path.display()));
+    let sidecar = if key_path.extension().is_some_and(|ext| ext == "pub") {
+        None
+    } else {
+        fs::read_to_string(&sidecar_path)
+            .ok()
+            .and_then(|text| PublicKey::from_openssh(text.trim()).ok())
+    };
+    (sidecar_path, sidecar)
+}
+
+fn parse_key(contents: &str) -> Result<ParsedKey> {
+    if contents.contains("BEGIN OPENSSH PRIVATE KEY") {
+        let key = PrivateKey::from_openssh(contents)?;
+        let public = key.public_key().clone();
+        Ok(ParsedKey {
+            format: KeyFormat::Openssh,
+            encrypted: key.is_encrypted(),
+            family: key_family(public.key_data()),
+            public: Some(public),
+        })
+    } else if contents.starts_with("PuTTY-User-Key-File-") {
+        let (public, encrypted) = ppk::inspect(contents)?;
+        Ok(ParsedKey {
+            format: KeyFormat::Ppk,
+            encrypted,
+            family: key_family(public.key_data()),
+            public: Some(public),
+        })
+    } else if contents.starts_with("-----BEGIN ") {
+        let pem = Pem::decode(contents)?;
+        let (format, family) = pem_format(&pem.label)?;
+        if format == KeyFormat::PemDsa {
+            // DSA is only reported from its .pub file; OpenSSH no longer accepts it
+            return Ok(ParsedKey {
+                format,
+                encrypted: pem.is_encrypted(),
+                family,
+                public: None,
+            });
+        }
+        if pem.is_encrypted() {
+            return Ok(ParsedKey::encrypted(format, family));
+        }
+        let keypair = pem_keypair(&pem.label, &pem.der)?;
+        Ok(ParsedKey::plain(format, KeyData::try_from(&keypair)?))
+    } else if let Ok(public) = PublicKey::from_openssh(contents) {
+        Ok(ParsedKey {
+            format: KeyFormat::PublicKey,
+            encrypted: false,
+            family: key_family(public.key_data()),
+            public: Some(public),
+        })
+    } else {
+        Err(anyhow!("Unrecognized key format"))
+    }
+}
+
+fn pem_format(label: &str) -> Result<(KeyFormat, &'static str)> {
+    match label {
+        "RSA PRIVATE KEY" => Ok((KeyFormat::PemPkcs1, "rsa")),
+        "EC PRIVATE KEY" => Ok((KeyFormat::PemSec1, "ecdsa")),
+        "DSA PRIVATE KEY" => Ok((KeyFormat::PemDsa, "dsa")),
+        "PRIVATE KEY" | "ENCRYPTED PRIVATE KEY" => Ok((KeyFormat::Pkcs8, "unknown")),
+        _ => Err(anyhow!("Unrecognized PEM block: {}", label)),
+    }
+}
+
+/// Decodes an unencrypted PKCS#1, SEC1 or PKCS#8 private key.
+fn pem_keypair(label: &str, der: &[u8]) -> Result<KeypairData> {
+    match label {
+        "RSA PRIVATE KEY" => rsa_keypair(&rsa::RsaPrivateKey::from_pkcs1_der(der)?),
+        "EC PRIVATE KEY" => {
+            if let Ok(key) = p256::SecretKey::from_sec1_der(der) {
+                ecdsa_keypair(EcdsaCurve::NistP256, &key.to_bytes())
+            } else if let Ok(key) = p384::SecretKey::from_sec1_der(der) {
+                ecdsa_keypair(EcdsaCurve::NistP384, &key.to_bytes())
+            } else {
+                let key = p521::SecretKey::from_sec1_der(der)
+                    .map_err(|e| anyhow!("Unsupported EC private key: {}", e))?;
+                ecdsa_keypair(EcdsaCurve::NistP521, &key.to_bytes())
+            }
+        }
+        "PRIVATE KEY" => pkcs8_keypair(der),
+        "DSA PRIVATE KEY" => Err(anyhow!("DSA keys are not supported")),
+        _ => Err(anyhow!("Unrecognized PEM block: {}", label)),
+    }
+}
+
+fn pkcs8_keypair(der: &[u8]) -> Result<KeypairData> {
+    use pkcs8::DecodePrivateKey;
+
+    if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_der(der) {
+        return Ok(KeypairData::from(Ed25519Keypair::from(key)));
+    }
+    if let Ok(key) = rsa::RsaPrivateKey::from_pkcs8_der(der) {
+        return rsa_keypair(&key);
+    }
+    if let Ok(key) = p256::SecretKey::from_pkcs8_der(der) {
+        ecdsa_keypair(EcdsaCurve::NistP256, &key.to_bytes())
+    } else if let Ok(key) = p384::SecretKey::from_pkcs8_der(der) {
+        ecdsa_keypair(EcdsaCurve::NistP384, &key.to_bytes())
+    } else if let Ok(key) = p521::SecretKey::from_pkcs8_der(der) {
+        ecdsa_keypair(EcdsaCurve::NistP521, &key.to_bytes())
+    } else {
+        Err(anyhow!("Unsupported PKCS#8 private key"))
+    }
+}
+
+fn rsa_keypair(key: &rsa::RsaPrivateKey) -> Result<KeypairData> {
+    Ok(KeypairData::from(RsaKeypair::try_from(key)?))
+}
+
+/// Builds an ECDSA keypair from its private scalar, which may carry a
+/// leading zero or be shorter than the field size.
+pub(crate) fn ecdsa_keypair(curve: EcdsaCurve, scalar: &[u8]) -> Result<KeypairData> {
+    let size = match curve {
+        EcdsaCurve::NistP256 => 32,
+        EcdsaCurve::NistP384 => 48,
+        EcdsaCurve::NistP521 => 66,
+    };
+    let start = scalar.iter().position(|&b| b != 0).unwrap_or(scalar.len());
+    let scalar = &scalar[start..];
+    if scalar.len() > size {
+        return Err(anyhow!("Invalid {} private key", curve));
+    }
+    let mut padded = vec![0u8; size - scalar.len()];
+    padded.extend_from_slice(scalar);
+
+    let keypair = match curve {
+        EcdsaCurve::NistP256 => {
+            let secret = p256::SecretKey::from_slice(&padded)?;
+            EcdsaKeypair::NistP256 {
+                public: secret.public_key().to_encoded_point(false),
+                private: secret.into(),
+            }
+        }
+        EcdsaCurve::NistP384 => {
+            let secret = p384::SecretKey::from_slice(&padded)?;
+            EcdsaKeypair::NistP384 {
+                public: secret.public_key().to_encoded_point(false),
+                private: secret.into(),
+            }
+        }
+        EcdsaCurve::NistP521 => {
+            let secret = p521::SecretKey::from_slice(&padded)?;
+            EcdsaKeypair::NistP521 {
+                public: secret.public_key().to_encoded_point(false),
+                private: secret.into(),
+            }
+        }
+    };
+    Ok(KeypairData::from(keypair))
+}
+
+/// A PEM block with its RFC 1421 headers (`Proc-Type`, `DEK-Info`).
+struct Pem {
+    label: String,
+    headers: HashMap<String, String>,
+    der: Vec<u8>,
+}
+
+impl Pem {
+    fn decode(contents: &str) -> Result<Self> {
+        let mut lines = contents.lines().map(str::trim);
+        let label = lines
+            .next()
+            .and_then(|line| line.strip_prefix("-----BEGIN "))
+            .and_then(|line| line.strip_suffix("-----"))
+            .ok_or_else(|| anyhow!("Invalid PEM file"))?
+            .to_string();
+
+        let mut headers = HashMap::new();
+        let mut body = String::new();
+        for line in lines {
+            if line.starts_with("-----END ") {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(": ") {
+                headers.insert(name.to_string(), value.to_string());
+            } else {
+                body.push_str(line);
+            }
+        }
+
+        Ok(Self {
+            label,
+            headers,
+            der: STANDARD.decode(body)?,
+        })
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.label == "ENCRYPTED PRIVATE KEY"
+            || self
+                .headers
+                .get("Proc-Type")
+                .is_some_and(|value| value.contains("ENCRYPTED"))
+    }
+
+    /// Returns the label and DER of the decrypted key.
+    fn decrypt(&self, passphrase: &str) -> Result<(String, Vec<u8>)> {
+        if self.label == "ENCRYPTED PRIVATE KEY" {
+            let info = pkcs8::EncryptedPrivateKeyInfo::try_from(self.der.as_slice())?;
+            let document = info
+                .decrypt(passphrase)
+                .map_err(|_| anyhow!("Incorrect passphrase"))?;
+            return Ok(("PRIVATE KEY".to_string(), document.as_bytes().to_vec()));
+        }
+
+        let dek_info = self
+            .headers
+            .get("DEK-Info")
+            .ok_or_else(|| anyhow!("Encrypted PEM key has no DEK-Info header"))?;
+        let (cipher, iv) = dek_info
+            .split_once(',')
+            .ok_or_else(|| anyhow!("Invalid DEK-Info header"))?;
+        let key_len = match cipher {
+            "AES-128-CBC" => 16,
+            "AES-192-CBC" => 24,
+            "AES-256-CBC" => 32,
+            _ => return Err(anyhow!("Unsupported PEM cipher {}", cipher)),
+        };
+        let iv = decode_hex(iv)?;
+        if iv.len() != 16 {
+            return Err(anyhow!("Invalid DEK-Info header"));
+        }
+        let key = evp_bytes_to_key(passphrase.as_bytes(), &iv[..8], key_len);
+        let der = aes_cbc(&key, &iv, &self.der, false)?;
+        Ok((self.label.clone(), der))
+    }
+}
+
+fn encode_pem(label: &str, headers: &[(&str, String)], der: &[u8]) -> String {
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for (name, value) in headers {
+        out.push_str(&format!("{}: {}\n", name, value));
+    }
+    if !headers.is_empty() {
+        out.push('\n');
+    }
+    let body = STANDARD.encode(der);
+    for chunk in body.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+/// OpenSSL's legacy PEM key derivation: chained MD5 over passphrase and salt.
+fn evp_bytes_to_key(passphrase: &[u8], salt: &[u8], len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(len + 16);
+    let mut block: Vec<u8> = Vec::new();
+    while key.len() < len {
+        block = Md5::new()
+            .chain_update(&block)
+            .chain_update(passphrase)
+            .chain_update(salt)
+            .finalize()
+            .to_vec();
+        key.extend_from_slice(&block);
+    }
+    key.truncate(len);
+    key
+}
+
+fn aes_cbc(key: &[u8], iv: &[u8], data: &[u8], encrypt: bool) -> Result<Vec<u8>> {
+    use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+
+    macro_rules! run {
+        ($cipher:ty) => {
+            if encrypt {
+                Ok(cbc::Encryptor::<$cipher>::new_from_slices(key, iv)
+                    .map_err(|_| anyhow!("Invalid cipher parameters"))?
+                    .encrypt_padded_vec_mut::<Pkcs7>(data))
+            } else {
+                cbc::Decryptor::<$cipher>::new_from_slices(key, iv)
+                    .map_err(|_| anyhow!("Invalid cipher parameters"))?
+                    .decrypt_padded_vec_mut::<Pkcs7>(data)
+                    .map_err(|_| anyhow!("Incorrect passphrase"))
+            }
+        };
+    }
+
+    match key.len() {
+        16 => run!(aes::Aes128),
+        24 => run!(aes::Aes192),
+        32 => run!(aes::Aes256),
+        _ => Err(anyhow!("Invalid cipher parameters")),
+    }
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("Invalid hex value"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex value")))
+        .collect()
+}
+
+fn key_family(data: &KeyData) -> &'static str {
+    match data {
+        KeyData::Ed25519(_) => "ed25519",
+        KeyData::Ecdsa(_) => "ecdsa",
+        KeyData::Rsa(_) => "rsa",
+        KeyData::Dsa(_) => "dsa",
+        KeyData::SkEd25519(_) => "ed25519-sk",
+        KeyData::SkEcdsaSha2NistP256(_) => "ecdsa-sk",
+        _ => "unknown",
+    }
+}
+
+fn key_curve(data: &KeyData) -> Option<&'static str> {
+    match data {
+        KeyData::Ecdsa(key) => Some(key.curve().as_str()),
+        KeyData::SkEcdsaSha2NistP256(_) => Some(EcdsaCurve::NistP256.as_str()),
+        _ => None,
+    }
+}
+
+fn key_bits(data: &KeyData) -> Option<u32> {
+    match data {
+        KeyData::Ed25519(_) | KeyData::SkEd25519(_) => Some(256),
+        KeyData::Ecdsa(key) => Some(match key.curve() {
+            EcdsaCurve::NistP256 => 256,
+            EcdsaCurve::NistP384 => 384,
+            EcdsaCurve::NistP521 => 521,
+        }),
+        KeyData::SkEcdsaSha2NistP256(_) => Some(256),
+        KeyData::Rsa(key) => mpint_bits(&key.n),
+        KeyData::Dsa(key) => mpint_bits(&key.p),
+        _ => None,
+    }
+}
+
+fn mpint_bits(value: &Mpint) -> Option<u32> {
+    let bytes = value.as_positive_bytes()?;
+    let first = *bytes.first()?;
+    Some((bytes.len() as u32 - 1) * 8 + (8 - first.leading_zeros()))
+}
+
+/// Matches the `[ED25519 256]` box title `ssh-keygen -lv` prints.
+fn randomart_header(data: &KeyData, bits: Option<u32>) -> String {
+    let family = key_family(data).to_uppercase();
+    match bits {
+        Some(bits) => format!("[{} {}]", family, bits),
+        None => format!("[{}]", family),
+    }
+}
+
+fn md5_fingerprint(public: &PublicKey) -> Result<String> {
+    let digest = Md5::digest(public.to_bytes()?);
+    let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("MD5:{}", hex.join(":")))
+}
+
+/// Reports whether a private key needs a passphrase before it can be used.
+pub fn is_key_encrypted(key_path: &Path) -> Result<bool> {
+    let contents = fs::read_to_string(key_path)?;
 
     if contents.contains("BEGIN OPENSSH PRIVATE KEY") {
-        // Parse OpenSSH format
-        if contents.contains("ssh-ed25519") {
-            Ok("ed25519".to_string())
-        } else if contents.contains("ecdsa") {
-            Ok("ecdsa".to_string())
-        } else if contents.contains("ssh-rsa") {
-            Ok("rsa".to_string())
-        } else {
-            Ok("unknown".to_string())
-        }
-    } else if contents.contains("BEGIN RSA PRIVATE KEY") {
-        Ok("rsa".to_string())
-    } else if contents.contains("BEGIN EC PRIVATE KEY") {
-        Ok("ecdsa".to_string())
-    } else if contents.contains("BEGIN DSA PRIVATE KEY") {
-        Ok("dsa".to_string())
+        // The cipher name is the first field after the magic, in cleartext
+        let body: String = contents
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let blob = STANDARD.decode(body.trim())?;
+        let rest = blob
+            .strip_prefix(b"openssh-key-v1\0")
+            .ok_or_else(|| anyhow!("Invalid OpenSSH private key"))?;
+        if rest.len() < 4 {
+            return Err(anyhow!("Invalid OpenSSH private key"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let cipher = rest
+            .get(4..4 + len)
+            .ok_or_else(|| anyhow!("Invalid OpenSSH private key"))?;
+        Ok(cipher != b"none")
     } else {
-        Ok("unknown".to_string())
+        Ok(contents.contains("Proc-Type: 4,ENCRYPTED")
+            || contents.contains("BEGIN ENCRYPTED PRIVATE KEY"))
     }
 }
 
-pub fn generate_ed25519_keypair(output_path: &str) -> Result<String> {
-    let private_key_path = output_path;
-    let public_key_path = format!("{}.pub", output_path);
+/// Key types offered by the key generator.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    #[default]
+    Ed25519,
+    EcdsaP256,
+    EcdsaP384,
+    EcdsaP521,
+    #[serde(rename = "rsa-3072")]
+    Rsa3072,
+    #[serde(rename = "rsa-4096")]
+    Rsa4096,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyGenOptions {
+    pub algorithm: KeyAlgorithm,
+    /// Encrypts the private key with bcrypt-pbkdf + AES-256-CTR when set.
+    pub passphrase: Option<String>,
+    pub comment: Option<String>,
+}
+
+/// Generates a keypair in OpenSSH format at `output_path` and `output_path.pub`,
+/// returning the public key line.
+pub fn generate_keypair(output_path: &str, options: &KeyGenOptions) -> Result<String> {
+    // Checked up front so a slow RSA generation isn't wasted
+    ensure_vacant(Path::new(output_path))?;
+
+    let mut rng = OsRng;
+    let key_data = match options.algorithm {
+        KeyAlgorithm::Ed25519 => KeypairData::from(Ed25519Keypair::random(&mut rng)),
+        KeyAlgorithm::EcdsaP256 => {
+            KeypairData::from(EcdsaKeypair::random(&mut rng, EcdsaCurve::NistP256)?)
+        }
+        KeyAlgorithm::EcdsaP384 => {
+            KeypairData::from(EcdsaKeypair::random(&mut rng, EcdsaCurve::NistP384)?)
+        }
+        KeyAlgorithm::EcdsaP521 => {
+            KeypairData::from(EcdsaKeypair::random(&mut rng, EcdsaCurve::NistP521)?)
+        }
+        KeyAlgorithm::Rsa3072 => KeypairData::from(RsaKeypair::random(&mut rng, 3072)?),
+        KeyAlgorithm::Rsa4096 => KeypairData::from(RsaKeypair::random(&mut rng, 4096)?),
+    };
+    let comment = options.comment.as_deref().unwrap_or("gterm-generated");
+    let private_key = PrivateKey::new(key_data, comment)?;
+
+    write_new_keypair(
+        Path::new(output_path),
+        &private_key,
+        options.passphrase.as_deref(),
+    )
+}
+
+/// Converts a PuTTY `.ppk` key to OpenSSH format at `output_path`, protected
+/// by the same passphrase, and returns the public key line.
+pub fn import_ppk(ppk_path: &Path, output_path: &Path, passphrase: Option<&str>) -> Result<String> {
+    ensure_vacant(output_path)?;
+    let contents = fs::read_to_string(ppk_path)?;
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    let private_key = ppk::decode(contents.trim(), passphrase)?;
+    write_new_keypair(output_path, &private_key, passphrase)
+}
+
+/// Target encodings for `convert_key`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConvertFormat {
+    /// `BEGIN OPENSSH PRIVATE KEY`.
+    Openssh,
+    /// Traditional PKCS#1 (RSA) or SEC1 (ECDSA) PEM, as `ssh-keygen -m PEM` writes.
+    Pem,
+}
 
-    // Use ssh-keygen to generate ed25519 key
-    let output = Command::new("ssh-keygen")
-        .args(&[
-            "-t",
-            "ed25519",
-            "-f",
-            private_key_path,
-            "-N",
-            "", // No passphrase
-            "-C",
-            "gterm-generated",
-        ])
-        .output()?;
+/// Loads and decrypts a private key in any format `inspect_key` understands.
+/// Keys without an embedded comment take the one from their `.pub` file.
+pub fn load_private_key(key_path: &Path, passphrase: Option<&str>) -> Result<PrivateKey> {
+    let contents = fs::read_to_string(key_path)?;
+    let contents = contents.trim();
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    let need_passphrase = || anyhow!("The key is encrypted; a passphrase is required");
 
-    if !output.status.success() {
-        return Err(anyhow!(
-            "Failed to generate keypair: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ));
+    if contents.contains("BEGIN OPENSSH PRIVATE KEY") {
+        let key = PrivateKey::from_openssh(contents)?;
+        if !key.is_encrypted() {
+            return Ok(key);
+        }
+        return key
+            .decrypt(passphrase.ok_or_else(need_passphrase)?)
+            .map_err(|_| anyhow!("Incorrect passphrase"));
     }
+    if contents.starts_with("PuTTY-User-Key-File-") {
+        return ppk::decode(contents, passphrase);
+    }
+
+    let pem = Pem::decode(contents)?;
+    let (label, der) = if pem.is_encrypted() {
+        pem.decrypt(passphrase.ok_or_else(need_passphrase)?)?
+    } else {
+        (pem.label.clone(), pem.der.clone())
+    };
+    let keypair = pem_keypair(&label, &der)?;
+
+    let (_, sidecar) = read_sidecar(key_path);
+    let comment = sidecar
+        .as_ref()
+        .map(|public| public.comment().to_string())
+        .unwrap_or_default();
+    Ok(PrivateKey::new(keypair, comment)?)
+}
+
+/// Adds, changes or removes a key's passphrase in place, returning the path of
+/// the backup copy.
+///
+/// PEM keys stay PEM; PKCS#8 keys are rewritten in OpenSSH format, as
+/// `ssh-keygen -p` does.
+pub fn change_passphrase(
+    key_path: &Path,
+    old_passphrase: Option<&str>,
+    new_passphrase: Option<&str>,
+) -> Result<PathBuf> {
+    let format = match inspect_key(key_path)?.format {
+        KeyFormat::PemPkcs1 | KeyFormat::PemSec1 => ConvertFormat::Pem,
+        KeyFormat::Openssh | KeyFormat::Pkcs8 => ConvertFormat::Openssh,
+        KeyFormat::Ppk => {
+            return Err(anyhow!(
+                "Import the PuTTY key before changing its passphrase"
+            ))
+        }
+        KeyFormat::PemDsa => return Err(anyhow!("DSA keys are not supported")),
+        KeyFormat::PublicKey => return Err(anyhow!("Not a private key")),
+    };
+    let key = load_private_key(key_path, old_passphrase)?;
+    rewrite_key(key_path, &key, format, new_passphrase)
+}
 
-    // Read public key
-    let public_key = fs::read_to_string(&public_key_path)?;
+/// Rewrites a key in place in another format, keeping its passphrase, and
+/// returns the path of the backup copy.
+pub fn convert_key(
+    key_path: &Path,
+    format: ConvertFormat,
+    passphrase: Option<&str>,
+) -> Result<PathBuf> {
+    match inspect_key(key_path)?.format {
+        KeyFormat::Ppk => return Err(anyhow!("Use the PuTTY import to convert .ppk files")),
+        KeyFormat::PemDsa => return Err(anyhow!("DSA keys are not supported")),
+        _ => {}
+    }
+    let key = load_private_key(key_path, passphrase)?;
+    rewrite_key(key_path, &key, format, passphrase)
+}
+
+fn rewrite_key(
+    key_path: &Path,
+    key: &PrivateKey,
+    format: ConvertFormat,
+    passphrase: Option<&str>,
+) -> Result<PathBuf> {
+    let contents = encode_private_key(key, format, passphrase)?;
+    let backup = backup_file(key_path)?;
+    write_private_file(key_path, contents.as_bytes())?;
+
+    let public_key_path = format!("{}.pub", key_path.display());
+    if !Path::new(&public_key_path).exists() {
+        fs::write(
+            &public_key_path,
+            format!("{}\n", key.public_key().to_openssh()?),
+        )?;
+    }
+
+    Ok(backup)
+}
+
+fn encode_private_key(
+    key: &PrivateKey,
+    format: ConvertFormat,
+    passphrase: Option<&str>,
+) -> Result<String> {
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    match format {
+        ConvertFormat::Openssh => {
+            let key = match passphrase {
+                Some(passphrase) => key.encrypt(&mut OsRng, passphrase)?,
+                None => key.clone(),
+            };
+            Ok(key.to_openssh(LineEnding::LF)?.to_string())
+        }
+        ConvertFormat::Pem => {
+            let (label, der) = match key.key_data() {
+                KeypairData::Rsa(keypair) => {
+                    use rsa::pkcs1::EncodeRsaPrivateKey;
+                    let der = rsa_private_key(keypair)?.to_pkcs1_der()?;
+                    ("RSA PRIVATE KEY", der.as_bytes().to_vec())
+                }
+                KeypairData::Ecdsa(keypair) => ("EC PRIVATE KEY", ecdsa_sec1_der(keypair)?),
+                _ => return Err(anyhow!("Only RSA and ECDSA keys can be stored as PEM")),
+            };
+
+            let Some(passphrase) = passphrase else {
+                return Ok(encode_pem(label, &[], &der));
+            };
+            // AES-128-CBC is what OpenSSL and ssh-keygen -m PEM use
+            let mut iv = [0u8; 16];
+            OsRng.fill_bytes(&mut iv);
+            let key = evp_bytes_to_key(passphrase.as_bytes(), &iv[..8], 16);
+            let encrypted = aes_cbc(&key, &iv, &der, true)?;
+            let iv_hex: String = iv.iter().map(|b| format!("{:02X}", b)).collect();
+            let headers = [
+                ("Proc-Type", "4,ENCRYPTED".to_string()),
+                ("DEK-Info", format!("AES-128-CBC,{}", iv_hex)),
+            ];
+            Ok(encode_pem(label, &headers, &encrypted))
+        }
+    }
+}
+
+/// ssh-key's own conversion passes `p` twice instead of `p` and `q`, so the
+/// key is rebuilt from its components here.
+pub(crate) fn rsa_private_key(keypair: &RsaKeypair) -> Result<rsa::RsaPrivateKey> {
+    let key = rsa::RsaPrivateKey::from_components(
+        rsa::BigUint::try_from(&keypair.public.n)?,
+        rsa::BigUint::try_from(&keypair.public.e)?,
+        rsa::BigUint::try_from(&keypair.private.d)?,
+        vec![
+            rsa::BigUint::try_from(&keypair.private.p)?,
+            rsa::BigUint::try_from(&keypair.private.q)?,
+        ],
+    )?;
+    key.validate()?;
+    Ok(key)
+}
+
+fn ecdsa_sec1_der(keypair: &EcdsaKeypair) -> Result<Vec<u8>> {
+    use pkcs8::der::Encode;
+
+    // OpenSSL needs the named-curve parameters, which SecretKey::to_sec1_der omits
+    let (private, public, curve_oid) = match keypair {
+        EcdsaKeypair::NistP256 { private, public } => {
+            (private.as_slice(), public.as_bytes(), "1.2.840.10045.3.1.7")
+        }
+        EcdsaKeypair::NistP384 { private, public } => {
+            (private.as_slice(), public.as_bytes(), "1.3.132.0.34")
+        }
+        EcdsaKeypair::NistP521 { private, public } => {
+            (private.as_slice(), public.as_bytes(), "1.3.132.0.35")
+        }
+    };
+    let der = ssh_key::sec1::EcPrivateKey {
+        private_key: private,
+        parameters: Some(ssh_key::sec1::EcParameters::NamedCurve(
+            pkcs8::ObjectIdentifier::new_unwrap(curve_oid),
+        )),
+        public_key: Some(public),
+    }
+    .to_der()?;
+    Ok(der)
+}
+
+/// Copies a file to `<name>.bak` (or `<name>.bak.N` if taken) before it is
+/// rewritten, keeping its permissions.
+pub(crate) fn backup_file(path: &Path) -> Result<PathBuf> {
+    let mut backup = PathBuf::from(format!("{}.bak", path.display()));
+    let mut n = 1;
+    while backup.exists() {
+        backup = PathBuf::from(format!("{}.bak.{}", path.display(), n));
+        n += 1;
+    }
+    fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// Replaces a file atomically with owner-only permissions.
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    // A fresh name opened with `create_new`, so a leftover file (whose mode
+    // `mode` wouldn't change) or a planted link never receives the contents
+    let tmp_path = PathBuf::from(format!("{}.tmp-{:016x}", path.display(), OsRng.next_u64()));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    let written = file.write_all(contents).and_then(|_| file.sync_all());
+    drop(file);
+
+    written
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })?;
+    Ok(())
+}
+
+fn ensure_vacant(output_path: &Path) -> Result<()> {
+    let public_key_path = format!("{}.pub", output_path.display());
+    if output_path.exists() || Path::new(&public_key_path).exists() {
+        return Err(anyhow!("A key already exists at {}", output_path.display()));
+    }
+    Ok(())
+}
+
+/// Writes a new private key (encrypted when a passphrase is given) and its
+/// `.pub` file, returning the public key line.
+fn write_new_keypair(
+    output_path: &Path,
+    private_key: &PrivateKey,
+    passphrase: Option<&str>,
+) -> Result<String> {
+    ensure_vacant(output_path)?;
+    if let Some(parent) = output_path.parent() {
+        create_private_dir(parent)?;
+    }
+
+    let public_key = private_key.public_key().to_openssh()?;
+    let contents = encode_private_key(private_key, ConvertFormat::Openssh, passphrase)?;
+    write_private_file(output_path, contents.as_bytes())?;
+    fs::write(
+        format!("{}.pub", output_path.display()),
+        format!("{}\n", public_key),
+    )?;
+
+    Ok(format!("{}\n", public_key))
+}
+
+/// Creates a key directory such as `~/.ssh` with owner-only access.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() || dir.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
 
-    // Set proper permissions on private key (0600)
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(private_key_path)?.permissions();
-        perms.set_mode(0o600);
-        fs::set_permissions(private_key_path, perms)?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
     }
 
-    Ok(public_key)
+    Ok(())
 }
@@ -0,0 +1,728 @@
+//! Reading, checking and editing OpenSSH `known_hosts` files, including
+//! hashed host names (`|1|salt|hmac`).
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use ssh2::Session;
+use ssh_key::rand_core::{OsRng, RngCore};
+use ssh_key::{HashAlg, PublicKey};
+use std::fs;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use super::auth::expand_tilde;
+use super::keygen;
+use super::prompt::PromptBroker;
+
+const HASH_MAGIC: &str = "|1|";
+
+/// Timeout for host key scans, in milliseconds.
+const SCAN_TIMEOUT_MS: u32 = 15_000;
+
+/// The file `SshConnection::connect` verifies host keys against.
+pub fn default_path() -> PathBuf {
+    expand_tilde("~/.ssh/known_hosts")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+/// Outcome of checking a server's host key against the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyStatus {
+    /// An entry for this host holds exactly this key.
+    Known,
+    /// Nothing recorded for this host and key type yet.
+    Unknown,
+    /// The host is recorded with a different key of the same type.
+    Changed,
+    /// The key is listed under `@revoked`.
+    Revoked,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KnownHost {
+    /// 1-based line number in the file.
+    pub line: usize,
+    pub marker: Option<Marker>,
+    /// Host patterns as written; hashed names stay in `|1|salt|hash` form.
+    pub hosts: Vec<String>,
+    pub hashed: bool,
+    pub key_type: String,
+    pub fingerprint: Option<String>,
+    pub comment: String,
+    /// Whether the entry applies to the host passed to `list`.
+    pub matches: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedHostKey {
+    pub host: String,
+    pub port: u16,
+    pub key_type: String,
+    pub fingerprint: String,
+    pub public_key: String,
+    pub status: HostKeyStatus,
+}
+
+/// What the user decided about a host key that is not known yet or changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyDecision {
+    /// Connect this time without touching the file.
+    AcceptOnce,
+    /// Connect and record the key, replacing older keys of the same type.
+    AcceptAndSave,
+    Reject,
+}
+
+/// Payload of `ssh-host-key-request:{session_id}`.
+#[derive(Debug, Clone, Serialize)]
+struct HostKeyRequest {
+    prompt_id: String,
+    host: String,
+    port: u16,
+    key_type: String,
+    fingerprint: String,
+    status: HostKeyStatus,
+    known_hosts_path: String,
+    /// Why the file could not be checked, when it could not.
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KnownHostsEdit {
+    /// Entries (or host names within entries) that were removed or rewritten.
+    pub changed: usize,
+    /// Hashed entries that could not be turned back into plain names because
+    /// none of the candidate host names matched them.
+    pub unresolved: usize,
+    pub backup_path: Option<String>,
+}
+
+/// One parsed line: `[@marker] patterns keytype base64 [comment]`.
+#[derive(Clone)]
+struct Entry {
+    marker: Option<Marker>,
+    patterns: Vec<String>,
+    key_type: String,
+    key: String,
+    comment: String,
+}
+
+impl Entry {
+    fn parse(text: &str) -> Option<Self> {
+        let mut fields = text.split_whitespace();
+        let mut first = fields.next()?;
+        let marker = match first {
+            "@cert-authority" => Some(Marker::CertAuthority),
+            "@revoked" => Some(Marker::Revoked),
+            _ => None,
+        };
+        if marker.is_some() {
+            first = fields.next()?;
+        }
+        let key_type = fields.next()?.to_string();
+        let key = fields.next()?.to_string();
+        Some(Self {
+            marker,
+            patterns: first.split(',').map(str::to_string).collect(),
+            key_type,
+            key,
+            comment: fields.collect::<Vec<_>>().join(" "),
+        })
+    }
+
+    fn render(&self) -> String {
+        let mut line = String::new();
+        match self.marker {
+            Some(Marker::CertAuthority) => line.push_str("@cert-authority "),
+            Some(Marker::Revoked) => line.push_str("@revoked "),
+            None => {}
+        }
+        line.push_str(&format!(
+            "{} {} {}",
+            self.patterns.join(","),
+            self.key_type,
+            self.key
+        ));
+        if !self.comment.is_empty() {
+            line.push(' ');
+            line.push_str(&self.comment);
+        }
+        line
+    }
+
+    /// Applies OpenSSH's rules: some pattern matches and no negated one does.
+    fn matches(&self, name: &str) -> bool {
+        let mut matched = false;
+        for pattern in &self.patterns {
+            match pattern.strip_prefix('!') {
+                Some(negated) if pattern_matches(negated, name) => return false,
+                Some(_) => {}
+                None => matched |= pattern_matches(pattern, name),
+            }
+        }
+        matched
+    }
+
+    fn key_bytes(&self) -> Option<Vec<u8>> {
+        STANDARD.decode(&self.key).ok()
+    }
+
+    fn to_known_host(&self, line: usize, name: Option<&str>) -> KnownHost {
+        KnownHost {
+            line,
+            marker: self.marker,
+            hosts: self.patterns.clone(),
+            hashed: self.patterns.iter().any(|p| p.starts_with(HASH_MAGIC)),
+            key_type: self.key_type.clone(),
+            fingerprint: PublicKey::from_openssh(&format!("{} {}", self.key_type, self.key))
+                .ok()
+                .map(|key| key.fingerprint(HashAlg::Sha256).to_string()),
+            comment: self.comment.clone(),
+            matches: name.is_some_and(|name| self.matches(name)),
+        }
+    }
+}
+
+/// Name as it appears in known_hosts: `host`, or `[host]:port` off port 22.
+pub fn host_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    if let Some(hashed) = pattern.strip_prefix(HASH_MAGIC) {
+        let Some((salt, hash)) = hashed.split_once('|') else {
+            return false;
+        };
+        let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+            return false;
+        };
+        return hash_name(&salt, name) == hash;
+    }
+    glob_matches(
+        pattern.to_lowercase().as_bytes(),
+        name.to_lowercase().as_bytes(),
+    )
+}
+
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_matches(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+fn hash_name(salt: &[u8], name: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC accepts any key length");
+    mac.update(name.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hashed_pattern(name: &str) -> String {
+    let mut salt = [0u8; 20];
+    OsRng.fill_bytes(&mut salt);
+    format!(
+        "{}{}|{}",
+        HASH_MAGIC,
+        STANDARD.encode(salt),
+        STANDARD.encode(hash_name(&salt, name))
+    )
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_line(text: &str) -> Option<Entry> {
+    let trimmed = text.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return None;
+    }
+    Entry::parse(trimmed)
+}
+
+/// Replaces the file's contents. A symlinked known_hosts (e.g. from a
+/// dotfile manager) is written through, so the link stays in place.
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let target = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    keygen::write_private_file(&target, contents)
+}
+
+/// Backs up the file and replaces it with `lines`.
+fn write_lines(path: &Path, lines: &[String]) -> Result<Option<String>> {
+    let backup = if path.exists() {
+        Some(keygen::backup_file(path)?.display().to_string())
+    } else {
+        None
+    };
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    write_file(path, contents.as_bytes())?;
+    Ok(backup)
+}
+
+/// Lists every entry, flagging the ones that apply to `host` when given.
+pub fn list(path: &Path, host: Option<(&str, u16)>) -> Result<Vec<KnownHost>> {
+    let name = host.map(|(host, port)| host_name(host, port));
+    Ok(read_lines(path)?
+        .iter()
+        .enumerate()
+        .filter_map(|(index, text)| {
+            parse_line(text).map(|entry| entry.to_known_host(index + 1, name.as_deref()))
+        })
+        .collect())
+}
+
+/// Checks a host key (SSH wire-format blob) against the file.
+pub fn check(path: &Path, host: &str, port: u16, key: &[u8]) -> Result<HostKeyStatus> {
+    let name = host_name(host, port);
+    let key_type = PublicKey::from_bytes(key)?.algorithm().as_str().to_string();
+
+    let mut status = HostKeyStatus::Unknown;
+    for entry in read_lines(path)?.iter().filter_map(|text| parse_line(text)) {
+        if !entry.matches(&name) {
+            continue;
+        }
+        let same_key = entry.key_bytes().as_deref() == Some(key);
+        match entry.marker {
+            Some(Marker::Revoked) if same_key => return Ok(HostKeyStatus::Revoked),
+            Some(_) => {}
+            None if same_key => status = HostKeyStatus::Known,
+            None if entry.key_type == key_type && status != HostKeyStatus::Known => {
+                status = HostKeyStatus::Changed
+            }
+            None => {}
+        }
+    }
+    Ok(status)
+}
+
+/// Appends an entry for the host, hashing the name when `hash` is set.
+pub fn add(path: &Path, host: &str, port: u16, key: &[u8], hash: bool) -> Result<()> {
+    let public = PublicKey::from_bytes(key)?;
+    let name = host_name(host, port);
+    let entry = Entry {
+        marker: None,
+        patterns: vec![if hash { hashed_pattern(&name) } else { name }],
+        key_type: public.algorithm().as_str().to_string(),
+        key: STANDARD.encode(key),
+        comment: String::new(),
+    };
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let mut lines = read_lines(path)?;
+    lines.push(entry.render());
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    write_file(path, contents.as_bytes())
+}
+
+/// Whether new entries should be hashed to match the rest of the file.
+pub fn prefers_hashing(path: &Path) -> bool {
+    let entries = list(path, None).unwrap_or_default();
+    !entries.is_empty() && entries.iter().all(|entry| entry.hashed)
+}
+
+/// Removes the host from every entry that names it, like `ssh-keygen -R`.
+/// Entries that list other hosts too keep those. `key_type` limits removal to
+/// keys of one type.
+pub fn remove_host(
+    path: &Path,
+    host: &str,
+    port: u16,
+    key_type: Option<&str>,
+) -> Result<KnownHostsEdit> {
+    let name = host_name(host, port);
+    let mut changed = 0;
+    let mut lines = Vec::new();
+
+    for text in read_lines(path)? {
+        let Some(mut entry) = parse_line(&text) else {
+            lines.push(text);
+            continue;
+        };
+        if entry.marker.is_some()
+            || !entry.matches(&name)
+            || key_type.is_some_and(|kt| kt != entry.key_type)
+        {
+            lines.push(text);
+            continue;
+        }
+
+        changed += 1;
+        // Keep the other names on a shared line, minus the ones that match
+        entry
+            .patterns
+            .retain(|pattern| pattern.starts_with('!') || !pattern_matches(pattern, &name));
+        if entry
+            .patterns
+            .iter()
+            .any(|pattern| !pattern.starts_with('!'))
+        {
+            lines.push(entry.render());
+        }
+    }
+
+    let backup_path = if changed > 0 {
+        write_lines(path, &lines)?
+    } else {
+        None
+    };
+    Ok(KnownHostsEdit {
+        changed,
+        unresolved: 0,
+        backup_path,
+    })
+}
+
+/// Switches the file between hashed and plain host names.
+///
+/// Hashing is one-way, so unhashing only works for names in `candidates`
+/// (e.g. the saved host profiles, as `host` or `[host]:port`). Wildcard and
+/// negated patterns are never hashed, since they would stop matching.
+pub fn set_hashed(path: &Path, hashed: bool, candidates: &[String]) -> Result<KnownHostsEdit> {
+    let mut changed = 0;
+    let mut unresolved = 0;
+    let mut lines = Vec::new();
+
+    for text in read_lines(path)? {
+        let Some(entry) = parse_line(&text) else {
+            lines.push(text);
+            continue;
+        };
+
+        let is_hashed = |pattern: &String| pattern.starts_with(HASH_MAGIC);
+        if hashed {
+            // Like ssh-keygen -H: lines with wildcards or negations stay as they
+            // are, and every other name gets a line of its own
+            let is_wildcard = entry.patterns.iter().any(|p| p.contains(['*', '?', '!']));
+            if is_wildcard || entry.patterns.iter().all(is_hashed) {
+                lines.push(text);
+                continue;
+            }
+            for pattern in &entry.patterns {
+                let pattern = if is_hashed(pattern) {
+                    pattern.clone()
+                } else {
+                    changed += 1;
+                    hashed_pattern(pattern)
+                };
+                lines.push(
+                    Entry {
+                        patterns: vec![pattern],
+                        ..entry.clone()
+                    }
+                    .render(),
+                );
+            }
+        } else {
+            let mut patterns = Vec::new();
+            for pattern in &entry.patterns {
+                if !is_hashed(pattern) {
+                    patterns.push(pattern.clone());
+                    continue;
+                }
+                match candidates
+                    .iter()
+                    .find(|name| pattern_matches(pattern, name))
+                {
+                    Some(name) => {
+                        patterns.push(name.clone());
+                        changed += 1;
+                    }
+                    None => {
+                        patterns.push(pattern.clone());
+                        unresolved += 1;
+                    }
+                }
+            }
+            lines.push(Entry { patterns, ..entry }.render());
+        }
+    }
+
+    let backup_path = if changed > 0 {
+        write_lines(path, &lines)?
+    } else {
+        None
+    };
+    Ok(KnownHostsEdit {
+        changed,
+        unresolved,
+        backup_path,
+    })
+}
+
+/// Connects and completes key exchange only, to read the server's host key.
+pub fn scan(path: &Path, host: &str, port: u16) -> Result<ScannedHostKey> {
+    let tcp = TcpStream::connect((host, port))
+        .map_err(|e| anyhow!("Failed to connect to {}:{}: {}", host, port, e))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(SCAN_TIMEOUT_MS);
+    session.handshake()?;
+
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("The server did not send a host key"))?;
+    let key = key.to_vec();
+    let _ = session.disconnect(None, "Host key scan", None);
+
+    let public = PublicKey::from_bytes(&key)?;
+    Ok(ScannedHostKey {
+        host: host.to_string(),
+        port,
+        key_type: public.algorithm().as_str().to_string(),
+        fingerprint: public.fingerprint(HashAlg::Sha256).to_string(),
+        public_key: public.to_openssh()?,
+        status: check(path, host, port, &key)?,
+    })
+}
+
+/// Re-scans the host and trusts its key if it still has `fingerprint` (as
+/// shown to the user from `scan`), replacing older keys of the same type.
+pub fn import(
+    path: &Path,
+    host: &str,
+    port: u16,
+    fingerprint: &str,
+    hash: bool,
+) -> Result<ScannedHostKey> {
+    let scanned = scan(path, host, port)?;
+    if scanned.fingerprint != fingerprint {
+        return Err(anyhow!(
+            "The host key changed between scans (now {}); not importing",
+            scanned.fingerprint
+        ));
+    }
+    if scanned.status == HostKeyStatus::Known {
+        return Ok(scanned);
+    }
+
+    remove_host(path, host, port, Some(&scanned.key_type))?;
+    let key = PublicKey::from_openssh(&scanned.public_key)?.to_bytes()?;
+    add(path, host, port, &key, hash)?;
+
+    Ok(ScannedHostKey {
+        status: HostKeyStatus::Known,
+        ..scanned
+    })
+}
+
+/// Verifies a freshly handshaken session against the default file. Unknown
+/// and changed keys are put to the user through `ssh-host-key-request:{session_id}`;
+/// no answer counts as a rejection. A file that cannot be read is reported
+/// in the prompt rather than failing the connection.
+pub async fn verify_session(
+    session: &Session,
+    host: &str,
+    port: u16,
+    prompts: &PromptBroker,
+    app_handle: &tauri::AppHandle,
+    session_id: &str,
+) -> Result<HostKeyStatus> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("The server did not send a host key"))?;
+    let key = key.to_vec();
+    let public = PublicKey::from_bytes(&key)?;
+    let path = default_path();
+
+    let (status, error) = match check(&path, host, port, &key) {
+        Ok(status) => (status, None),
+        Err(e) => (HostKeyStatus::Unknown, Some(e.to_string())),
+    };
+    match status {
+        HostKeyStatus::Known => return Ok(status),
+        HostKeyStatus::Revoked => {
+            return Err(anyhow!(
+                "The host key for {} is marked as revoked in {}",
+                host_name(host, port),
+                path.display()
+            ))
+        }
+        HostKeyStatus::Unknown | HostKeyStatus::Changed => {}
+    }
+
+    let event = format!("ssh-host-key-request:{}", session_id);
+    let decision: Option<HostKeyDecision> = prompts
+        .ask(app_handle, &event, |prompt_id| HostKeyRequest {
+            prompt_id,
+            host: host.to_string(),
+            port,
+            key_type: public.algorithm().as_str().to_string(),
+            fingerprint: public.fingerprint(HashAlg::Sha256).to_string(),
+            status,
+            known_hosts_path: path.display().to_string(),
+            error,
+        })
+        .await;
+
+    match decision {
+        Some(HostKeyDecision::AcceptOnce) => Ok(status),
+        Some(HostKeyDecision::AcceptAndSave) => {
+            let key_type = public.algorithm().as_str().to_string();
+            if status == HostKeyStatus::Changed {
+                remove_host(&path, host, port, Some(&key_type))?;
+            }
+            add(&path, host, port, &key, prefers_hashing(&path))
+                .map_err(|e| anyhow!("Failed to save the host key to {}: {}", path.display(), e))?;
+            Ok(status)
+        }
+        Some(HostKeyDecision::Reject) | None => Err(anyhow!(
+            "The host key for {} ({}) was not accepted",
+            host_name(host, port),
+            public.fingerprint(HashAlg::Sha256)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIJiZEONYFMuyt6moMn6E0bG/hiFj/OC25kxqhdPy9JlJ";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIB1iUrAR73j6zfe9YKIKEzgaLpyRk99EZShaT3MZfu/H";
+
+    /// Written by `ssh-keygen -H` for `example.com` and `[example.com]:2222`.
+    const HASHED: &str = "|1|YXCWE+O8u2fZNTnGgfym88Td86k=|sUyI1POlzyMlBTmI24aY5fLSBbc=";
+    const HASHED_PORT: &str = "|1|uYw9uWA2qGC14BZwHy3ZCbTIaSo=|RcWctt0m5t2j7GTatoJPkvqF94s=";
+
+    fn entry(patterns: &str) -> Entry {
+        Entry::parse(&format!("{} ssh-ed25519 {}", patterns, KEY)).unwrap()
+    }
+
+    /// A file of its own per test, since tests run in parallel.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("gterm-known-hosts-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn hashed_names_match_only_their_host() {
+        assert!(entry(HASHED).matches("example.com"));
+        assert!(!entry(HASHED).matches("example.org"));
+        assert!(!entry(HASHED).matches(&host_name("example.com", 2222)));
+        assert!(entry(HASHED_PORT).matches(&host_name("example.com", 2222)));
+        assert!(!entry("|1|not base64|x").matches("example.com"));
+    }
+
+    #[test]
+    fn new_hashed_patterns_match_their_name() {
+        let pattern = hashed_pattern("[example.com]:2222");
+        assert!(pattern.starts_with(HASH_MAGIC));
+        assert!(pattern_matches(&pattern, "[example.com]:2222"));
+        assert!(!pattern_matches(&pattern, "example.com"));
+    }
+
+    #[test]
+    fn wildcards_and_negations_follow_openssh() {
+        let wildcard = entry("*.example.com,!bad.example.com");
+        assert!(wildcard.matches("a.example.com"));
+        assert!(wildcard.matches("A.Example.COM"));
+        assert!(!wildcard.matches("bad.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(entry("host?").matches("host1"));
+        assert!(!entry("host?").matches("host"));
+    }
+
+    #[test]
+    fn check_reports_each_status() {
+        let path = temp_file(
+            "check",
+            &format!(
+                "# comment\n{} ssh-ed25519 {}\n@revoked revoked.example ssh-ed25519 {}\n",
+                HASHED, KEY, OTHER_KEY
+            ),
+        );
+        let key = STANDARD.decode(KEY).unwrap();
+        let other = STANDARD.decode(OTHER_KEY).unwrap();
+
+        assert_eq!(
+            check(&path, "example.com", 22, &key).unwrap(),
+            HostKeyStatus::Known
+        );
+        assert_eq!(
+            check(&path, "example.com", 22, &other).unwrap(),
+            HostKeyStatus::Changed
+        );
+        assert_eq!(
+            check(&path, "example.org", 22, &key).unwrap(),
+            HostKeyStatus::Unknown
+        );
+        assert_eq!(
+            check(&path, "revoked.example", 22, &other).unwrap(),
+            HostKeyStatus::Revoked
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edits_write_through_a_symlinked_file() {
+        let target = temp_file("target", &format!("{} ssh-ed25519 {}\n", HASHED, KEY));
+        let link = target.with_file_name(format!("gterm-known-hosts-link-{}", std::process::id()));
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let other = STANDARD.decode(OTHER_KEY).unwrap();
+        add(&link, "example.org", 22, &other, false).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            check(&target, "example.org", 22, &other).unwrap(),
+            HostKeyStatus::Known
+        );
+
+        let edit = remove_host(&link, "example.com", 22, None).unwrap();
+        assert_eq!(edit.changed, 1);
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let key = STANDARD.decode(KEY).unwrap();
+        assert_eq!(
+            check(&target, "example.com", 22, &key).unwrap(),
+            HostKeyStatus::Unknown
+        );
+
+        for path in [link, target] {
+            fs::remove_file(path).unwrap();
+        }
+        if let Some(backup) = edit.backup_path {
+            fs::remove_file(backup).unwrap();
+        }
+    }
+}
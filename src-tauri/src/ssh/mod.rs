@@ -1,3 +1,18 @@
+pub mod agent;
+pub mod auth;
+pub mod authorized_keys;
 pub mod connection;
+pub mod credentials;
+pub mod diagnostics;
+pub mod key_inventory;
 pub mod keygen;
+pub mod known_hosts;
+pub mod ppk;
+pub mod prompt;
+pub mod remote;
+pub mod remote_edit;
 pub mod sftp;
+pub mod sync;
+pub mod totp;
+pub mod transfer;
+pub mod transfer_queue;
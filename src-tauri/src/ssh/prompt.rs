@@ -0,0 +1,66 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::Emitter;
+use tokio::sync::{oneshot, Mutex};
+
+/// How long the backend waits for the user before treating a prompt as
+/// cancelled. Kept below sshd's default `LoginGraceTime` of 120 s, so a prompt
+/// during login times out here rather than as an unexplained disconnect.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Round-trips questions to the frontend: a prompt event goes out with a
+/// `prompt_id`, and the matching command hands the answer back here.
+#[derive(Clone, Default)]
+pub struct PromptBroker {
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<serde_json::Value>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl PromptBroker {
+    /// Emits `event` with the payload built from a fresh prompt id and waits
+    /// for the answer. Returns `None` if the user cancelled, the answer did
+    /// not parse, or nobody answered in time.
+    pub async fn ask<P, T>(
+        &self,
+        app_handle: &tauri::AppHandle,
+        event: &str,
+        payload: impl FnOnce(String) -> P,
+    ) -> Option<T>
+    where
+        P: Serialize + Clone,
+        T: DeserializeOwned,
+    {
+        let prompt_id = format!("prompt-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(prompt_id.clone(), tx);
+
+        if app_handle.emit(event, payload(prompt_id.clone())).is_err() {
+            self.pending.lock().await.remove(&prompt_id);
+            return None;
+        }
+
+        let answer = tokio::time::timeout(PROMPT_TIMEOUT, rx).await;
+        self.pending.lock().await.remove(&prompt_id);
+
+        match answer {
+            Ok(Ok(value)) => serde_json::from_value(value).ok(),
+            _ => None,
+        }
+    }
+
+    pub async fn respond(&self, prompt_id: &str, answer: serde_json::Value) -> Result<()> {
+        let tx = self
+            .pending
+            .lock()
+            .await
+            .remove(prompt_id)
+            .ok_or_else(|| anyhow!("No pending prompt with id {}", prompt_id))?;
+        tx.send(answer)
+            .map_err(|_| anyhow!("Prompt {} is no longer waiting", prompt_id))
+    }
+}
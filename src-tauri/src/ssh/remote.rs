@@ -0,0 +1,142 @@
+//! One-off commands run over an established session, next to the interactive
+//! shell. The session is non-blocking once connected, so every libssh2 call
+//! here is retried until it stops returning EAGAIN.
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use ssh2::{Channel, ErrorCode, Session};
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use super::connection::{SshConnection, LIBSSH2_ERROR_EAGAIN};
+
+/// Upper bound for a single remote command, including reading its output.
+const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// A libssh2 call that was still returning EAGAIN at its deadline. The
+/// request may still be in flight, so its reply can arrive later.
+#[derive(Debug, thiserror::Error)]
+#[error("Timed out waiting for the server")]
+pub(crate) struct TimedOut;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecOutput {
+    pub exit_status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
+/// Runs `command` through the remote user's shell, optionally feeding `stdin`.
+pub async fn exec(
+    connection: &SshConnection,
+    command: &str,
+    stdin: Option<Vec<u8>>,
+) -> Result<ExecOutput> {
+    let session = connection
+        .get_session()
+        .await
+        .lock()
+        .await
+        .clone()
+        .ok_or_else(|| anyhow!("No active session"))?;
+    let command = command.to_string();
+
+    tokio::task::spawn_blocking(move || exec_blocking(&session, &command, stdin.as_deref())).await?
+}
+
+fn exec_blocking(session: &Session, command: &str, stdin: Option<&[u8]>) -> Result<ExecOutput> {
+    let deadline = Instant::now() + EXEC_TIMEOUT;
+    let mut channel = retry(deadline, || session.channel_session())?;
+    retry(deadline, || channel.exec(command))?;
+
+    if let Some(data) = stdin {
+        write_all(&mut channel, data, deadline)?;
+    }
+    retry(deadline, || channel.send_eof())?;
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let mut progressed = read_some(&mut channel, &mut buffer, &mut stdout)?;
+        progressed |= read_some(&mut channel.stderr(), &mut buffer, &mut stderr)?;
+
+        if !progressed {
+            if channel.eof() {
+                break;
+            }
+            if Instant::now() > deadline {
+                return Err(anyhow!("Timed out waiting for the remote command"));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    retry(deadline, || channel.wait_close())?;
+    Ok(ExecOutput {
+        exit_status: channel.exit_status()?,
+        stdout: String::from_utf8_lossy(&stdout).to_string(),
+        stderr: String::from_utf8_lossy(&stderr).to_string(),
+    })
+}
+
+/// Reads whatever is available into `out`, returning whether anything was read.
+fn read_some(reader: &mut impl Read, buffer: &mut [u8], out: &mut Vec<u8>) -> Result<bool> {
+    match reader.read(buffer) {
+        Ok(0) => Ok(false),
+        Ok(n) => {
+            out.extend_from_slice(&buffer[..n]);
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_all(channel: &mut Channel, data: &[u8], deadline: Instant) -> Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        match channel.write(&data[written..]) {
+            Ok(n) => written += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() > deadline {
+                    return Err(anyhow!("Timed out sending data to the remote command"));
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Repeats a libssh2 call until it completes or `deadline` passes.
+pub(crate) fn retry<T>(
+    deadline: Instant,
+    mut op: impl FnMut() -> std::result::Result<T, ssh2::Error>,
+) -> Result<T> {
+    loop {
+        match op() {
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                if Instant::now() > deadline {
+                    return Err(TimedOut.into());
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            result => return result.map_err(Into::into),
+        }
+    }
+}
+
+/// Quotes a value for POSIX `sh`.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
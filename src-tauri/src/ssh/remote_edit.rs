@@ -0,0 +1,358 @@
+//! Editing remote files in a local editor. The file is downloaded into a
+//! private workspace and opened; every save is uploaded back, unless the
+//! remote copy changed in the meantime.
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tauri::Emitter;
+use tokio::sync::Mutex;
+
+use super::connection::SshConnection;
+use super::sftp::{self, RemoteVersion};
+
+/// How often the local copy is checked for saves.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EditState {
+    Uploaded,
+    /// The remote file changed since it was downloaded; the save was not
+    /// uploaded.
+    Conflict,
+    Failed,
+    /// The connection went away, so the edit was closed.
+    Closed,
+}
+
+/// Payload of the `remote-edit:{session_id}` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct EditEvent {
+    pub edit_id: String,
+    pub remote_path: String,
+    pub local_path: String,
+    pub state: EditState,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EditSessionInfo {
+    pub edit_id: String,
+    pub session_id: String,
+    pub remote_path: String,
+    pub local_path: String,
+    /// Set while a save is held back because the remote file changed.
+    pub conflict: bool,
+}
+
+struct EditSession {
+    session_id: String,
+    remote_path: String,
+    local_path: PathBuf,
+    connection: SshConnection,
+    app_handle: tauri::AppHandle,
+    /// Remote version the local copy is based on.
+    remote_version: Option<RemoteVersion>,
+    /// Hash of the contents last downloaded or uploaded, which is also what
+    /// the remote file holds unless someone else changed it.
+    synced_hash: Vec<u8>,
+    /// Hash of the save that was held back by a conflict, so it is reported once.
+    conflict_hash: Option<Vec<u8>>,
+}
+
+impl EditSession {
+    fn info(&self, edit_id: &str) -> EditSessionInfo {
+        EditSessionInfo {
+            edit_id: edit_id.to_string(),
+            session_id: self.session_id.clone(),
+            remote_path: self.remote_path.clone(),
+            local_path: self.local_path.display().to_string(),
+            conflict: self.conflict_hash.is_some(),
+        }
+    }
+
+    fn emit(&self, edit_id: &str, state: EditState, error: Option<String>) {
+        let _ = self.app_handle.emit(
+            &format!("remote-edit:{}", self.session_id),
+            EditEvent {
+                edit_id: edit_id.to_string(),
+                remote_path: self.remote_path.clone(),
+                local_path: self.local_path.display().to_string(),
+                state,
+                error,
+            },
+        );
+    }
+}
+
+/// An open edit. Its state is only locked briefly; `syncing` keeps uploads
+/// one at a time without blocking `list` or `close` during the round-trip.
+struct OpenEdit {
+    session: Mutex<EditSession>,
+    syncing: Mutex<()>,
+}
+
+#[derive(Clone, Default)]
+pub struct RemoteEditor {
+    sessions: Arc<Mutex<HashMap<String, Arc<OpenEdit>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RemoteEditor {
+    /// Downloads `remote_path`, opens it with `editor` (a command line that
+    /// gets the file appended, such as `code --wait`) or the system's default
+    /// application, and starts uploading saves.
+    pub async fn open(
+        &self,
+        connection: SshConnection,
+        session_id: &str,
+        remote_path: &str,
+        editor: Option<&str>,
+        app_handle: tauri::AppHandle,
+    ) -> Result<EditSessionInfo> {
+        let edit_id = format!("edit-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let file_name = Path::new(remote_path)
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file", remote_path))?;
+        let dir = workspace_dir()?.join(&edit_id);
+        std::fs::create_dir_all(&dir)?;
+        let local_path = dir.join(file_name);
+
+        let (contents, version) = sftp::read_file(&connection, remote_path).await?;
+        std::fs::write(&local_path, &contents)?;
+
+        let session = EditSession {
+            session_id: session_id.to_string(),
+            remote_path: remote_path.to_string(),
+            local_path: local_path.clone(),
+            connection,
+            app_handle,
+            remote_version: Some(version),
+            synced_hash: Sha256::digest(&contents).to_vec(),
+            conflict_hash: None,
+        };
+        let info = session.info(&edit_id);
+        let edit = OpenEdit {
+            session: Mutex::new(session),
+            syncing: Mutex::new(()),
+        };
+        self.sessions
+            .lock()
+            .await
+            .insert(edit_id.clone(), Arc::new(edit));
+
+        if let Err(e) = launch_editor(editor, &local_path) {
+            self.close(&edit_id).await?;
+            return Err(e);
+        }
+
+        let editor = self.clone();
+        tokio::spawn(async move { editor.watch(edit_id).await });
+        Ok(info)
+    }
+
+    pub async fn list(&self) -> Vec<EditSessionInfo> {
+        let sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(id, session)| (id.clone(), Arc::clone(session)))
+            .collect();
+        let mut list = Vec::new();
+        for (id, edit) in sessions {
+            list.push(edit.session.lock().await.info(&id));
+        }
+        list.sort_by(|a, b| a.edit_id.cmp(&b.edit_id));
+        list
+    }
+
+    async fn edit(&self, edit_id: &str) -> Option<Arc<OpenEdit>> {
+        self.sessions.lock().await.get(edit_id).cloned()
+    }
+
+    /// Uploads the local copy now. With `force`, a remote change is overwritten.
+    pub async fn upload(&self, edit_id: &str, force: bool) -> Result<EditState> {
+        let edit = self
+            .edit(edit_id)
+            .await
+            .ok_or_else(|| anyhow!("No remote edit with id {}", edit_id))?;
+        let local_path = edit.session.lock().await.local_path.clone();
+
+        let contents = std::fs::read(&local_path)?;
+        let hash = Sha256::digest(&contents).to_vec();
+        let result = sync(&edit, edit_id, contents, hash, force).await;
+        if let Err(ref e) = result {
+            let session = edit.session.lock().await;
+            session.emit(edit_id, EditState::Failed, Some(e.to_string()));
+        }
+        result
+    }
+
+    /// Stops watching and deletes the local copy. Saves made after the last
+    /// upload are lost.
+    pub async fn close(&self, edit_id: &str) -> Result<()> {
+        let session = self
+            .sessions
+            .lock()
+            .await
+            .remove(edit_id)
+            .ok_or_else(|| anyhow!("No remote edit with id {}", edit_id))?;
+        if let Some(dir) = session.session.lock().await.local_path.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        Ok(())
+    }
+
+    /// Closes every edit made through the connection `session_id`, e.g. when
+    /// it is disconnected.
+    pub async fn close_session(&self, session_id: &str) {
+        let edits: Vec<_> = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(id, edit)| (id.clone(), Arc::clone(edit)))
+            .collect();
+        for (edit_id, edit) in edits {
+            if edit.session.lock().await.session_id == session_id {
+                let _ = self.close(&edit_id).await;
+            }
+        }
+    }
+
+    /// Polls the local copy until the session is closed, or its connection
+    /// goes away. Editors often save by writing a new file and renaming it,
+    /// which path-based polling follows.
+    async fn watch(&self, edit_id: String) {
+        let mut last_seen = None;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(edit) = self.edit(&edit_id).await else {
+                return;
+            };
+
+            let (local_path, connection) = {
+                let session = edit.session.lock().await;
+                (session.local_path.clone(), session.connection.clone())
+            };
+            if connection.get_session().await.lock().await.is_none() {
+                edit.session
+                    .lock()
+                    .await
+                    .emit(&edit_id, EditState::Closed, None);
+                let _ = self.close(&edit_id).await;
+                return;
+            }
+
+            let seen = local_stamp(&local_path);
+            if seen.is_none() || seen == last_seen {
+                continue;
+            }
+            last_seen = seen;
+
+            let Ok(contents) = std::fs::read(&local_path) else {
+                continue;
+            };
+            let hash = Sha256::digest(&contents).to_vec();
+            {
+                let session = edit.session.lock().await;
+                if hash == session.synced_hash || session.conflict_hash.as_ref() == Some(&hash) {
+                    continue;
+                }
+            }
+            if let Err(e) = sync(&edit, &edit_id, contents, hash, false).await {
+                let session = edit.session.lock().await;
+                session.emit(&edit_id, EditState::Failed, Some(e.to_string()));
+            }
+        }
+    }
+}
+
+/// Uploads `contents` unless the remote file changed since the local copy was
+/// made from it. A change within the same second that keeps the size is
+/// caught by comparing contents.
+async fn sync(
+    edit: &OpenEdit,
+    edit_id: &str,
+    contents: Vec<u8>,
+    hash: Vec<u8>,
+    force: bool,
+) -> Result<EditState> {
+    let _syncing = edit.syncing.lock().await;
+    let (connection, remote_path, remote_version, synced_hash) = {
+        let session = edit.session.lock().await;
+        (
+            session.connection.clone(),
+            session.remote_path.clone(),
+            session.remote_version,
+            session.synced_hash.clone(),
+        )
+    };
+
+    if !force {
+        let current = sftp::file_version(&connection, &remote_path).await?;
+        let changed = current != remote_version
+            || sftp::hash_remote_file(&connection, &remote_path).await?
+                != sftp::hex_digest(&synced_hash);
+        if changed {
+            let mut session = edit.session.lock().await;
+            session.conflict_hash = Some(hash);
+            session.emit(edit_id, EditState::Conflict, None);
+            return Ok(EditState::Conflict);
+        }
+    }
+
+    let version = sftp::replace_file(&connection, &remote_path, contents).await?;
+    let mut session = edit.session.lock().await;
+    session.remote_version = Some(version);
+    session.synced_hash = hash;
+    session.conflict_hash = None;
+    session.emit(edit_id, EditState::Uploaded, None);
+    Ok(EditState::Uploaded)
+}
+
+fn local_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Private per-process directory holding the local copies.
+fn workspace_dir() -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("gterm-edit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+fn launch_editor(editor: Option<&str>, path: &Path) -> Result<()> {
+    let Some(editor) = editor.filter(|editor| !editor.trim().is_empty()) else {
+        return open::that_detached(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e));
+    };
+
+    // The path goes in as "$1" so it never needs quoting
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    let mut child = tokio::process::Command::new(shell)
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("gterm-edit")
+        .arg(path)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to start editor: {}", e))?;
+    tokio::spawn(async move {
+        let _ = child.wait().await;
+    });
+    Ok(())
+}
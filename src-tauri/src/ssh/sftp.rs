@@ -1,121 +1,1859 @@
+//! File operations over the connection's SFTP channel.
+//!
+//! The session stays non-blocking for the shell, so SFTP calls run on a
+//! blocking thread and retry while libssh2 reports EAGAIN. Files move in
+//! small chunks and each call holds the session only briefly, so typing in
+//! the terminal keeps flowing during a large transfer.
+
 use anyhow::{anyhow, Result};
+use glob::Pattern;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use ssh2::{ErrorCode, File, FileStat, FileType, OpenFlags, OpenType, RenameFlags, Sftp};
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::connection::{SshConnection, LIBSSH2_ERROR_EAGAIN};
+use super::remote::{self, retry, shell_quote, TimedOut};
+use super::transfer::{FileAction, FileVersion, Transfer, TransferCancelled, TransferOptions};
+
+/// libssh2's "no more directory entries" return code.
+const LIBSSH2_ERROR_FILE: i32 = -16;
+
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Longest wait for a single SFTP request before the operation fails.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
 
-use super::connection::SshConnection;
+/// How much of the already copied data `verify_resume` compares: the end of
+/// it, where an interrupted write would have left damage.
+const VERIFY_WINDOW: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Socket,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Unknown,
+}
+
+impl From<FileType> for FileKind {
+    fn from(file_type: FileType) -> Self {
+        match file_type {
+            FileType::RegularFile => FileKind::File,
+            FileType::Directory => FileKind::Directory,
+            FileType::Symlink => FileKind::Symlink,
+            FileType::Socket => FileKind::Socket,
+            FileType::NamedPipe => FileKind::Fifo,
+            FileType::CharDevice => FileKind::CharDevice,
+            FileType::BlockDevice => FileKind::BlockDevice,
+            FileType::Other(_) => FileKind::Unknown,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     pub name: String,
     pub path: String,
+    /// Also set for links to directories, so they can be opened.
     pub is_dir: bool,
     pub size: u64,
     pub modified: i64,
+    pub accessed: i64,
     pub permissions: u32,
+    /// `ls -l` style, e.g. `drwxr-xr-x`.
+    pub permissions_text: String,
+    /// The entry itself, not what a link points to.
+    pub file_type: FileKind,
+    pub link_target: Option<String>,
+    /// The link's target doesn't exist.
+    pub broken_link: bool,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+/// User and group names of a remote host, keyed by id.
+#[derive(Debug, Default)]
+pub struct OwnerNames {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+/// The connection's SFTP channel. libssh2 keeps the state of a request that
+/// returned EAGAIN on the channel itself, not per call, so requests from two
+/// threads must not overlap: the second would pick up the first one's reply.
+/// Every call therefore goes through `request`, `request_at` or `request_io`,
+/// which hold `busy` until the request has completed.
+pub struct SftpChannel {
+    sftp: Sftp,
+    busy: std::sync::Mutex<()>,
+}
+
+impl SftpChannel {
+    pub fn new(sftp: Sftp) -> Self {
+        Self {
+            sftp,
+            busy: std::sync::Mutex::new(()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.busy.lock().unwrap_or_else(|e| e.into_inner())
+    }
 }
 
-pub async fn list_directory(
+impl std::ops::Deref for SftpChannel {
+    type Target = Sftp;
+
+    fn deref(&self) -> &Sftp {
+        &self.sftp
+    }
+}
+
+/// Runs `op` against the connection's SFTP channel on a blocking thread.
+async fn with_sftp<T, F>(connection: &SshConnection, op: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&SftpChannel) -> Result<T> + Send + 'static,
+{
+    let sftp = connection.sftp().await?;
+    let result = tokio::task::spawn_blocking(move || op(&sftp)).await?;
+
+    // A broken channel (rather than an SFTP status such as "no such file") is
+    // reopened on the next call, as is one with a timed-out request whose late
+    // reply would otherwise be taken for the next request's
+    let channel_failed = result.as_ref().err().is_some_and(|e| {
+        e.downcast_ref::<TimedOut>().is_some()
+            || e.downcast_ref::<ssh2::Error>()
+                .is_some_and(|e| matches!(e.code(), ErrorCode::Session(_)))
+    });
+    if channel_failed {
+        connection.reset_sftp().await;
+    }
+    result
+}
+
+/// Retries one SFTP request until it stops returning EAGAIN, keeping other
+/// requests on the channel out until then.
+fn request<T>(
+    sftp: &SftpChannel,
+    op: impl FnMut() -> std::result::Result<T, ssh2::Error>,
+) -> Result<T> {
+    let _busy = sftp.lock();
+    retry(Instant::now() + REQUEST_TIMEOUT, op)
+}
+
+/// Times one `realpath(".")` round trip, for the connection's RTT. Returns
+/// `None` while another request holds the channel, as the wait would be
+/// counted too.
+pub(crate) fn measure_rtt(sftp: &SftpChannel) -> Option<Result<Duration>> {
+    let _busy = sftp.busy.try_lock().ok()?;
+    let started = Instant::now();
+    let deadline = started + REQUEST_TIMEOUT;
+    loop {
+        match sftp.realpath(Path::new(".")) {
+            Ok(_) => return Some(Ok(started.elapsed())),
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                if Instant::now() > deadline {
+                    return Some(Err(TimedOut.into()));
+                }
+                // Finer than POLL_INTERVAL, which would round the sample up
+                std::thread::sleep(Duration::from_micros(50));
+            }
+            Err(e) => return Some(Err(e.into())),
+        }
+    }
+}
+
+/// Like `request`, but an SFTP status such as "permission denied" becomes a
+/// readable error naming `path`.
+fn request_at<T>(
+    sftp: &SftpChannel,
+    path: &Path,
+    op: impl FnMut() -> std::result::Result<T, ssh2::Error>,
+) -> Result<T> {
+    request(sftp, op).map_err(|e| readable(e, path))
+}
+
+fn readable(e: anyhow::Error, path: &Path) -> anyhow::Error {
+    let status = match e.downcast_ref::<ssh2::Error>().map(|e| e.code()) {
+        Some(ErrorCode::SFTP(code)) => status_text(code),
+        _ => None,
+    };
+    match status {
+        Some(status) => e.context(format!("{}: {}", status, path.display())),
+        None => e,
+    }
+}
+
+/// Text for the SFTP status codes a server is likely to send.
+fn status_text(code: i32) -> Option<&'static str> {
+    Some(match code {
+        2 => "No such file",
+        3 => "Permission denied",
+        // OpenSSH answers most refused requests (such as removing a non-empty
+        // directory) with this generic status
+        4 => "Operation failed",
+        5 => "Bad message",
+        8 => "Operation not supported by the server",
+        10 => "No such path",
+        11 => "File already exists",
+        12 => "Write protected",
+        14 => "No space left on the server",
+        15 => "Quota exceeded",
+        18 => "Directory not empty",
+        19 => "Not a directory",
+        20 => "Invalid file name",
+        21 => "Too many levels of symbolic links",
+        _ => return None,
+    })
+}
+
+/// Like `request`, for the `std::io` calls on an open `File`.
+fn request_io<T>(sftp: &SftpChannel, mut op: impl FnMut() -> std::io::Result<T>) -> Result<T> {
+    let _busy = sftp.lock();
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    loop {
+        match op() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() > deadline {
+                    return Err(TimedOut.into());
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            result => return result.map_err(Into::into),
+        }
+    }
+}
+
+fn write_all(sftp: &SftpChannel, file: &mut File, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        let n = request_io(sftp, || file.write(data))?;
+        if n == 0 {
+            return Err(anyhow!("The server stopped accepting data"));
+        }
+        data = &data[n..];
+    }
+    Ok(())
+}
+
+/// Reads a remote directory, leaving out `.`, `..` and names that aren't a
+/// single path component. The stats come from READDIR, so symlinks are
+/// reported as links rather than their targets.
+fn read_dir(sftp: &SftpChannel, dir_path: &Path) -> Result<Vec<(PathBuf, FileStat)>> {
+    let mut dir = request_at(sftp, dir_path, || sftp.opendir(dir_path))?;
+    let mut entries = Vec::new();
+    loop {
+        let (name, stat) = match request(sftp, || dir.readdir()) {
+            Ok(entry) => entry,
+            Err(e)
+                if e.downcast_ref::<ssh2::Error>()
+                    .is_some_and(|e| e.code() == ErrorCode::Session(LIBSSH2_ERROR_FILE)) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        };
+        if is_plain_name(&name) {
+            entries.push((name, stat));
+        }
+    }
+    let _ = request(sftp, || dir.close());
+    Ok(entries)
+}
+
+/// Whether a name sent by the server is one ordinary path component. Names
+/// such as `../../.bashrc` or `/home/me/.ssh/authorized_keys` from a hostile
+/// server would otherwise lead downloads outside the chosen directory.
+pub fn is_plain_name(name: &Path) -> bool {
+    let mut components = name.components();
+    !name.to_string_lossy().contains('/')
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+}
+
+pub async fn list_directory(connection: &SshConnection, path: &str) -> Result<Vec<FileEntry>> {
+    let dir_path = Path::new(path).to_path_buf();
+    let names = owner_names(connection).await;
+
+    let mut entries = with_sftp(connection, move |sftp| {
+        let entries: Vec<FileEntry> = read_dir(sftp, &dir_path)?
+            .into_iter()
+            .map(|(name, stat)| file_entry(sftp, &dir_path, &name, &stat, &names))
+            .collect();
+        Ok(entries)
+    })
+    .await?;
+
+    // Sort: directories first, then alphabetically
+    entries.sort_by(|a, b| match (a.is_dir, b.is_dir) {
+        (true, false) => std::cmp::Ordering::Less,
+        (false, true) => std::cmp::Ordering::Greater,
+        _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+    });
+
+    Ok(entries)
+}
+
+/// Builds the entry for `name` from its READDIR (lstat) attributes.
+fn file_entry(
+    sftp: &SftpChannel,
+    dir_path: &Path,
+    name: &Path,
+    stat: &FileStat,
+    names: &OwnerNames,
+) -> FileEntry {
+    let path = dir_path.join(name);
+    let file_type = FileKind::from(stat.file_type());
+
+    let mut is_dir = stat.is_dir();
+    let mut link_target = None;
+    let mut broken_link = false;
+    if file_type == FileKind::Symlink {
+        link_target = request(sftp, || sftp.readlink(&path))
+            .ok()
+            .map(|target| target.to_string_lossy().to_string());
+        match request(sftp, || sftp.stat(&path)) {
+            Ok(target) => is_dir = target.is_dir(),
+            Err(_) => broken_link = true,
+        }
+    }
+
+    let perm = stat.perm.unwrap_or(0);
+    FileEntry {
+        name: name.to_string_lossy().to_string(),
+        path: path.to_string_lossy().to_string(),
+        is_dir,
+        size: stat.size.unwrap_or(0),
+        modified: stat.mtime.unwrap_or(0) as i64,
+        accessed: stat.atime.unwrap_or(0) as i64,
+        permissions: perm,
+        permissions_text: permissions_text(file_type, perm),
+        file_type,
+        link_target,
+        broken_link,
+        uid: stat.uid,
+        gid: stat.gid,
+        owner: stat.uid.and_then(|uid| names.users.get(&uid).cloned()),
+        group: stat.gid.and_then(|gid| names.groups.get(&gid).cloned()),
+    }
+}
+
+fn permissions_text(file_type: FileKind, perm: u32) -> String {
+    let type_char = match file_type {
+        FileKind::Directory => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::Socket => 's',
+        FileKind::Fifo => 'p',
+        FileKind::CharDevice => 'c',
+        FileKind::BlockDevice => 'b',
+        FileKind::File | FileKind::Unknown => '-',
+    };
+    // (read bit, special bit, special char) for user, group and others
+    let classes = [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')];
+
+    let mut text = String::with_capacity(10);
+    text.push(type_char);
+    for (shift, special_bit, special) in classes {
+        let bits = (perm >> shift) & 0o7;
+        text.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        text.push(match (bits & 0o1 != 0, perm & special_bit != 0) {
+            (true, true) => special,
+            (false, true) => special.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    text
+}
+
+/// The connection's id-to-name tables, read from the host's `/etc/passwd` and
+/// `/etc/group` the first time they are needed. Names from directory services
+/// such as LDAP aren't in those files, so their entries show only the ids.
+async fn owner_names(connection: &SshConnection) -> Arc<OwnerNames> {
+    if let Some(names) = connection.owner_names().await {
+        return names;
+    }
+
+    let names = with_sftp(connection, |sftp| {
+        Ok(OwnerNames {
+            users: read_id_names(sftp, Path::new("/etc/passwd")),
+            groups: read_id_names(sftp, Path::new("/etc/group")),
+        })
+    })
+    .await;
+    match names {
+        Ok(names) => {
+            let names = Arc::new(names);
+            connection.set_owner_names(Arc::clone(&names)).await;
+            names
+        }
+        // The channel failed; try again with the next listing
+        Err(_) => Arc::default(),
+    }
+}
+
+/// Parses `name:password:id:...` lines, as found in `/etc/passwd` and
+/// `/etc/group`. Unreadable files give an empty table.
+fn read_id_names(sftp: &SftpChannel, path: &Path) -> HashMap<u32, String> {
+    let mut contents = Vec::new();
+    let read = (|| {
+        let mut file = request(sftp, || sftp.open(path))?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let n = request_io(sftp, || file.read(&mut buffer))?;
+            if n == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buffer[..n]);
+        }
+        request(sftp, || file.close())
+    })();
+    if read.is_err() {
+        return HashMap::new();
+    }
+
+    let mut names = HashMap::new();
+    for line in String::from_utf8_lossy(&contents).lines() {
+        let mut fields = line.split(':');
+        if let (Some(name), Some(_), Some(id)) = (fields.next(), fields.next(), fields.next()) {
+            if let Ok(id) = id.parse() {
+                // The first entry wins, as with getpwuid
+                names.entry(id).or_insert_with(|| name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Renames or moves `from` to `to`. Most servers refuse to replace an existing
+/// file.
+pub async fn rename(connection: &SshConnection, from: &str, to: &str) -> Result<()> {
+    let from = Path::new(from).to_path_buf();
+    let to = Path::new(to).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        request_at(sftp, &from, || {
+            sftp.rename(&from, &to, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE))
+        })
+    })
+    .await
+}
+
+/// Deletes a file, link or directory. Directories must be empty unless
+/// `recursive` is set; links are removed, never followed.
+pub async fn remove(connection: &SshConnection, path: &str, recursive: bool) -> Result<()> {
+    let path = Path::new(path).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        let stat = request_at(sftp, &path, || sftp.lstat(&path))?;
+        let remove_one = |path: &Path, stat: &FileStat| {
+            if stat.is_dir() {
+                request_at(sftp, path, || sftp.rmdir(path))
+            } else {
+                request_at(sftp, path, || sftp.unlink(path))
+            }
+        };
+        if recursive {
+            visit_tree(
+                sftp,
+                &path,
+                stat,
+                TreeOrder::ChildrenFirst,
+                &mut |path, stat| remove_one(path, stat),
+            )
+        } else {
+            remove_one(&path, &stat)
+        }
+    })
+    .await
+}
+
+/// Creates `path` and any missing parents, like `mkdir -p`.
+pub async fn create_directory(connection: &SshConnection, path: &str) -> Result<()> {
+    let path = Path::new(path).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        let mut current = PathBuf::new();
+        for component in path.components() {
+            current.push(component);
+            if current.parent().is_some() {
+                ensure_remote_dir(sftp, &current)?;
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Sets the permission bits of `path`, and with `recursive` of everything
+/// below it. Links inside the tree are skipped, as they have no modes of
+/// their own.
+pub async fn chmod(
     connection: &SshConnection,
     path: &str,
-) -> Result<Vec<FileEntry>> {
-    let session_arc = connection.get_session().await;
-    let session_guard = session_arc.lock().await;
+    mode: u32,
+    recursive: bool,
+) -> Result<()> {
+    let path = Path::new(path).to_path_buf();
 
-    let session = session_guard
-        .as_ref()
-        .ok_or_else(|| anyhow!("No active session"))?;
+    with_sftp(connection, move |sftp| {
+        let set_mode = |path: &Path, stat: &FileStat| {
+            let perm = (stat.perm.unwrap_or(0) & !0o7777) | (mode & 0o7777);
+            request_at(sftp, path, || {
+                sftp.setstat(path, stat_with(|s| s.perm = Some(perm)))
+            })
+        };
+        let stat = request_at(sftp, &path, || sftp.stat(&path))?;
+        if recursive {
+            visit_tree(
+                sftp,
+                &path,
+                stat,
+                TreeOrder::ParentFirst,
+                &mut |path, stat| {
+                    if stat.file_type().is_symlink() {
+                        Ok(())
+                    } else {
+                        set_mode(path, stat)
+                    }
+                },
+            )
+        } else {
+            set_mode(&path, &stat)
+        }
+    })
+    .await
+}
 
-    let sftp = session.sftp()?;
-    let dir_path = Path::new(path);
+/// Changes the numeric owner and/or group of `path`, and with `recursive` of
+/// everything below it. Links inside the tree are skipped.
+pub async fn chown(
+    connection: &SshConnection,
+    path: &str,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    recursive: bool,
+) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    let path = Path::new(path).to_path_buf();
 
-    let mut entries = Vec::new();
+    with_sftp(connection, move |sftp| {
+        // SETSTAT always carries both ids, so the one left alone is filled in
+        // from the current owner
+        let set_owner = |path: &Path, stat: &FileStat| {
+            let uid = uid.or(stat.uid);
+            let gid = gid.or(stat.gid);
+            request_at(sftp, path, || {
+                sftp.setstat(
+                    path,
+                    stat_with(|s| {
+                        s.uid = uid;
+                        s.gid = gid;
+                    }),
+                )
+            })
+        };
+        let stat = request_at(sftp, &path, || sftp.stat(&path))?;
+        if recursive {
+            visit_tree(
+                sftp,
+                &path,
+                stat,
+                TreeOrder::ParentFirst,
+                &mut |path, stat| {
+                    if stat.file_type().is_symlink() {
+                        Ok(())
+                    } else {
+                        set_owner(path, stat)
+                    }
+                },
+            )
+        } else {
+            set_owner(&path, &stat)
+        }
+    })
+    .await
+}
 
-    for (path_buf, stat) in sftp.readdir(dir_path)? {
-        let name = path_buf
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("")
-            .to_string();
+/// Creates a symbolic link at `link_path` pointing at `target`.
+pub async fn create_symlink(
+    connection: &SshConnection,
+    target: &str,
+    link_path: &str,
+) -> Result<()> {
+    let target = Path::new(target).to_path_buf();
+    let link_path = Path::new(link_path).to_path_buf();
+
+    // ssh2 takes the link's target first, then the link itself
+    with_sftp(connection, move |sftp| {
+        request_at(sftp, &link_path, || sftp.symlink(&target, &link_path))
+    })
+    .await
+}
+
+/// Returns the target of the symbolic link at `path`, as stored in the link.
+pub async fn read_link(connection: &SshConnection, path: &str) -> Result<String> {
+    let path = Path::new(path).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        let target = request_at(sftp, &path, || sftp.readlink(&path))?;
+        Ok(target.to_string_lossy().to_string())
+    })
+    .await
+}
 
-        let full_path = path_buf.to_string_lossy().to_string();
+/// Size and modification time of a remote file, used to notice that someone
+/// else changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteVersion {
+    pub size: u64,
+    pub modified: u64,
+}
 
-        entries.push(FileEntry {
-            name,
-            path: full_path,
-            is_dir: stat.is_dir(),
+impl From<&FileStat> for FileVersion {
+    fn from(stat: &FileStat) -> Self {
+        Self {
             size: stat.size.unwrap_or(0),
-            modified: stat.mtime.unwrap_or(0) as i64,
-            permissions: stat.perm.unwrap_or(0),
-        });
+            modified: stat.mtime.unwrap_or(0),
+        }
     }
+}
 
-    // Sort: directories first, then alphabetically
-    entries.sort_by(|a, b| {
-        match (a.is_dir, b.is_dir) {
-            (true, false) => std::cmp::Ordering::Less,
-            (false, true) => std::cmp::Ordering::Greater,
-            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+impl From<&FileStat> for RemoteVersion {
+    fn from(stat: &FileStat) -> Self {
+        Self {
+            size: stat.size.unwrap_or(0),
+            modified: stat.mtime.unwrap_or(0),
         }
-    });
+    }
+}
 
-    Ok(entries)
+/// The current version of `path`, or `None` if it no longer exists.
+pub async fn file_version(connection: &SshConnection, path: &str) -> Result<Option<RemoteVersion>> {
+    let path = Path::new(path).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        match request(sftp, || sftp.stat(&path)) {
+            Ok(stat) => Ok(Some(RemoteVersion::from(&stat))),
+            Err(e)
+                if e.downcast_ref::<ssh2::Error>().is_some_and(
+                    |e| matches!(e.code(), ErrorCode::SFTP(code) if code == 2 || code == 10),
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(readable(e, &path)),
+        }
+    })
+    .await
+}
+
+/// Reads a whole remote file into memory.
+pub async fn read_file(connection: &SshConnection, path: &str) -> Result<(Vec<u8>, RemoteVersion)> {
+    let path = Path::new(path).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        let mut file = request_at(sftp, &path, || sftp.open(&path))?;
+        let version = RemoteVersion::from(&request(sftp, || file.stat())?);
+        let mut contents = Vec::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let n = request_io(sftp, || file.read(&mut buffer))?;
+            if n == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buffer[..n]);
+        }
+        request(sftp, || file.close())?;
+        Ok((contents, version))
+    })
+    .await
 }
 
+/// Replaces the contents of `path` so readers see either the old or the new
+/// file, never a half-written one. The data goes to a temporary file next to
+/// the target, which then takes its place and its permissions.
+pub async fn replace_file(
+    connection: &SshConnection,
+    path: &str,
+    contents: Vec<u8>,
+) -> Result<RemoteVersion> {
+    // Write next to the real file, so editing through a link keeps the link
+    let target = {
+        let path = Path::new(path).to_path_buf();
+        with_sftp(connection, move |sftp| {
+            request_at(sftp, &path, || sftp.realpath(&path))
+        })
+        .await?
+    };
+    let name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", target.display()))?
+        .to_string_lossy()
+        .to_string();
+    let temp = target.with_file_name(format!(
+        ".{}.gterm-{}-{}",
+        name,
+        std::process::id(),
+        chrono::Utc::now().timestamp_millis()
+    ));
+
+    let written = {
+        let (target, temp) = (target.clone(), temp.clone());
+        with_sftp(connection, move |sftp| {
+            let original = request_at(sftp, &target, || sftp.stat(&target)).ok();
+            let mut file = request_at(sftp, &temp, || sftp.create(&temp))?;
+            write_all(sftp, &mut file, &contents)?;
+            request(sftp, || file.close())?;
+
+            if let Some(original) = original {
+                let perm = original.perm.unwrap_or(0o644) & 0o7777;
+                request_at(sftp, &temp, || {
+                    sftp.setstat(&temp, stat_with(|s| s.perm = Some(perm)))
+                })?;
+                // Only root can give files away; keep our own ownership otherwise
+                let _ = request(sftp, || {
+                    sftp.setstat(
+                        &temp,
+                        stat_with(|s| {
+                            s.uid = original.uid;
+                            s.gid = original.gid;
+                        }),
+                    )
+                });
+            }
+            Ok(())
+        })
+        .await
+    };
+    let result = match written {
+        Ok(()) => move_over(connection, &temp, &target).await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let temp = temp.clone();
+        let _ = with_sftp(connection, move |sftp| request(sftp, || sftp.unlink(&temp))).await;
+    }
+    result?;
+
+    with_sftp(connection, move |sftp| {
+        Ok(RemoteVersion::from(&request_at(sftp, &target, || {
+            sftp.stat(&target)
+        })?))
+    })
+    .await
+}
+
+/// Renames `from` over an existing `to`. SFTP version 3, which OpenSSH speaks,
+/// can't replace files by renaming, so this falls back to `mv` and, for
+/// accounts without a shell, to removing the target first.
+async fn move_over(connection: &SshConnection, from: &Path, to: &Path) -> Result<()> {
+    let renamed = {
+        let (from, to) = (from.to_path_buf(), to.to_path_buf());
+        with_sftp(connection, move |sftp| {
+            request(sftp, || {
+                sftp.rename(
+                    &from,
+                    &to,
+                    Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE),
+                )
+            })
+        })
+        .await
+    };
+    if renamed.is_ok() {
+        return Ok(());
+    }
+
+    let command = format!(
+        "mv -f -- {} {}",
+        shell_quote(&from.to_string_lossy()),
+        shell_quote(&to.to_string_lossy())
+    );
+    if remote::exec(connection, &command, None)
+        .await
+        .is_ok_and(|output| output.success())
+    {
+        return Ok(());
+    }
+
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+    with_sftp(connection, move |sftp| {
+        let _ = request(sftp, || sftp.unlink(&to));
+        request_at(sftp, &to, || sftp.rename(&from, &to, None))
+    })
+    .await
+}
+
+/// A `FileStat` with only the fields set by `set`, for SETSTAT requests.
+fn stat_with(set: impl FnOnce(&mut FileStat)) -> FileStat {
+    let mut stat = FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: None,
+        mtime: None,
+    };
+    set(&mut stat);
+    stat
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TreeOrder {
+    ParentFirst,
+    ChildrenFirst,
+}
+
+/// Calls `visit` for `path` and, when it is a directory, everything below it.
+/// `stat` comes from `lstat`, so links are visited but not followed.
+fn visit_tree(
+    sftp: &SftpChannel,
+    path: &Path,
+    stat: FileStat,
+    order: TreeOrder,
+    visit: &mut impl FnMut(&Path, &FileStat) -> Result<()>,
+) -> Result<()> {
+    if order == TreeOrder::ParentFirst {
+        visit(path, &stat)?;
+    }
+    if stat.is_dir() {
+        for (name, child) in read_dir(sftp, path)? {
+            visit_tree(sftp, &path.join(name), child, order, visit)?;
+        }
+    }
+    if order == TreeOrder::ChildrenFirst {
+        visit(path, &stat)?;
+    }
+    Ok(())
+}
+
+/// Copies `remote_path` to `local_path`, reporting progress through
+/// `transfer`. A cancelled download leaves no partial file behind.
 pub async fn download_file(
     connection: &SshConnection,
     remote_path: &str,
     local_path: &str,
+    transfer: &Transfer,
 ) -> Result<()> {
-    let session_arc = connection.get_session().await;
-    let session_guard = session_arc.lock().await;
+    let remote_path = Path::new(remote_path).to_path_buf();
+    let local_path = Path::new(local_path).to_path_buf();
+    let transfer = transfer.clone();
 
-    let session = session_guard
-        .as_ref()
-        .ok_or_else(|| anyhow!("No active session"))?;
+    with_sftp(connection, move |sftp| {
+        if let Some(size) = request_at(sftp, &remote_path, || sftp.stat(&remote_path))?.size {
+            transfer.set_total(size);
+        }
+        download_one(sftp, &remote_path, &local_path, &transfer).map(|_| ())
+    })
+    .await
+}
 
-    let sftp = session.sftp()?;
+/// Copies `local_path` to `remote_path`, reporting progress through
+/// `transfer`. A cancelled upload leaves no partial file behind.
+pub async fn upload_file(
+    connection: &SshConnection,
+    local_path: &str,
+    remote_path: &str,
+    transfer: &Transfer,
+) -> Result<()> {
+    let local_path = Path::new(local_path).to_path_buf();
+    let remote_path = Path::new(remote_path).to_path_buf();
+    let transfer = transfer.clone();
 
-    let mut remote_file = sftp.open(Path::new(remote_path))?;
-    let mut local_file = std::fs::File::create(local_path)?;
+    with_sftp(connection, move |sftp| {
+        transfer.set_total(std::fs::metadata(&local_path)?.len());
+        upload_one(sftp, &local_path, &remote_path, &transfer).map(|_| ())
+    })
+    .await
+}
 
-    let mut buffer = vec![0; 8192];
-    loop {
-        let n = remote_file.read(&mut buffer)?;
+/// Copies one file, settling a conflict with an existing destination first.
+/// Returns where the copy was written, or `None` if the file was skipped.
+fn download_one(
+    sftp: &SftpChannel,
+    remote_path: &Path,
+    local_path: &Path,
+    transfer: &Transfer,
+) -> Result<Option<PathBuf>> {
+    let source = FileVersion::from(&request_at(sftp, remote_path, || sftp.stat(remote_path))?);
+    let existing = std::fs::metadata(local_path).ok().map(|meta| FileVersion {
+        size: meta.len(),
+        modified: local_mtime(&meta),
+    });
+    let Some((local_path, action)) =
+        transfer.resolve_conflict(remote_path, source, local_path, existing, |path| {
+            path.symlink_metadata().is_ok()
+        })?
+    else {
+        return Ok(None);
+    };
+
+    let result: Result<u64> = (|| {
+        let mut remote_file = request_at(sftp, remote_path, || sftp.open(remote_path))?;
+        let size = request(sftp, || remote_file.stat())?.size.unwrap_or(0);
+        let mut local_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&local_path)?;
+        let existing = if action == FileAction::Resumed {
+            local_file.metadata()?.len()
+        } else {
+            0
+        };
+
+        let offset = resume_offset(
+            sftp,
+            transfer,
+            &mut remote_file,
+            size,
+            &mut local_file,
+            existing,
+        )?;
+        local_file.set_len(offset)?;
+        local_file.seek(SeekFrom::Start(offset))?;
+        remote_file.seek(SeekFrom::Start(offset))?;
+        transfer.skip(offset);
+        // From here on the file holds only what this transfer wrote
+        transfer.set_in_flight(Some(&local_path));
+
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            transfer.check_cancelled()?;
+            let n = request_io(sftp, || remote_file.read(&mut buffer))?;
+            if n == 0 {
+                break;
+            }
+            local_file.write_all(&buffer[..n])?;
+            transfer.advance(n)?;
+        }
+        request(sftp, || remote_file.close())?;
+        Ok(offset)
+    })();
+
+    match result {
+        Ok(offset) => {
+            transfer.set_in_flight(None);
+            transfer.report(remote_path, &local_path, resumed_or(offset, action));
+            Ok(Some(local_path))
+        }
+        Err(e) => {
+            if e.is::<TransferCancelled>() && !transfer.keeps_partial() {
+                transfer.set_in_flight(None);
+                let _ = std::fs::remove_file(&local_path);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Copies one file like `download_one`, in the other direction.
+fn upload_one(
+    sftp: &SftpChannel,
+    local_path: &Path,
+    remote_path: &Path,
+    transfer: &Transfer,
+) -> Result<Option<PathBuf>> {
+    let meta = std::fs::metadata(local_path)?;
+    let source = FileVersion {
+        size: meta.len(),
+        modified: local_mtime(&meta),
+    };
+    let existing = request(sftp, || sftp.stat(remote_path))
+        .ok()
+        .map(|stat| FileVersion::from(&stat));
+    let Some((remote_path, action)) =
+        transfer.resolve_conflict(local_path, source, remote_path, existing, |path| {
+            request(sftp, || sftp.lstat(path)).is_ok()
+        })?
+    else {
+        return Ok(None);
+    };
+    let written = remote_path.clone();
+    let remote_path = remote_path.as_path();
+    let existing = match existing {
+        Some(existing) if action == FileAction::Resumed => existing.size,
+        _ => 0,
+    };
+
+    let result: Result<u64> = (|| {
+        let mut local_file = std::fs::File::open(local_path)?;
+        let size = local_file.metadata()?.len();
+
+        // Reopen a partial upload without truncating it
+        let mut offset = 0;
+        let mut partial = None;
+        if existing > 0 {
+            let mut remote_file = request(sftp, || {
+                sftp.open_mode(
+                    remote_path,
+                    OpenFlags::READ | OpenFlags::WRITE,
+                    0o644,
+                    OpenType::File,
+                )
+            })?;
+            offset = resume_offset(
+                sftp,
+                transfer,
+                &mut local_file,
+                size,
+                &mut remote_file,
+                existing,
+            )?;
+            if offset > 0 {
+                partial = Some(remote_file);
+            } else {
+                request(sftp, || remote_file.close())?;
+            }
+        }
+        let mut remote_file = match partial {
+            Some(remote_file) => remote_file,
+            None => request_at(sftp, remote_path, || sftp.create(remote_path))?,
+        };
+        local_file.seek(SeekFrom::Start(offset))?;
+        remote_file.seek(SeekFrom::Start(offset))?;
+        transfer.skip(offset);
+        transfer.set_in_flight(Some(remote_path));
+
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            transfer.check_cancelled()?;
+            let n = local_file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            write_all(sftp, &mut remote_file, &buffer[..n])?;
+            transfer.advance(n)?;
+        }
+        request(sftp, || remote_file.close())?;
+        Ok(offset)
+    })();
+
+    match result {
+        Ok(offset) => {
+            transfer.set_in_flight(None);
+            transfer.report(local_path, remote_path, resumed_or(offset, action));
+            Ok(Some(written))
+        }
+        Err(e) => {
+            if e.is::<TransferCancelled>() && !transfer.keeps_partial() {
+                transfer.set_in_flight(None);
+                let _ = request(sftp, || sftp.unlink(remote_path));
+            }
+            Err(e)
+        }
+    }
+}
+
+/// What a finished copy counts as, once it is known whether the partial
+/// destination could be continued.
+fn resumed_or(offset: u64, action: FileAction) -> FileAction {
+    match action {
+        _ if offset > 0 => FileAction::Resumed,
+        FileAction::Resumed => FileAction::Overwritten,
+        action => action,
+    }
+}
+
+/// Where to continue copying a `size`-byte source into a partial destination
+/// that already holds `existing` bytes. Returns 0 to start over.
+fn resume_offset(
+    sftp: &SftpChannel,
+    transfer: &Transfer,
+    source: &mut (impl Read + Seek),
+    size: u64,
+    destination: &mut (impl Read + Seek),
+    existing: u64,
+) -> Result<u64> {
+    if existing == 0 || existing > size {
+        return Ok(0);
+    }
+    if transfer.verifies_resume() {
+        let len = existing.min(VERIFY_WINDOW);
+        let start = existing - len;
+        if digest_range(sftp, source, start, len)? != digest_range(sftp, destination, start, len)? {
+            return Ok(0);
+        }
+    }
+    Ok(existing)
+}
+
+fn digest_range(
+    sftp: &SftpChannel,
+    file: &mut (impl Read + Seek),
+    start: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(CHUNK_SIZE as u64) as usize;
+        let n = request_io(sftp, || file.read(&mut buffer[..want]))?;
         if n == 0 {
             break;
         }
-        local_file.write_all(&buffer[..n])?;
+        hasher.update(&buffer[..n]);
+        remaining -= n as u64;
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// What a recursive transfer does with symbolic links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Copy whatever the link points to. Broken links are left out.
+    Follow,
+    /// Recreate the link with the same target.
+    #[default]
+    CopyAsLink,
+    Skip,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TreeOptions {
+    #[serde(flatten)]
+    pub transfer: TransferOptions,
+    pub symlinks: SymlinkPolicy,
+    /// Glob patterns, matched against both the path relative to the copied
+    /// directory and the bare file name. When set, only matching files are
+    /// copied.
+    pub include: Vec<String>,
+    /// Same matching as `include`. Excluded directories are not entered.
+    pub exclude: Vec<String>,
+}
+
+struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    fn new(options: &TreeOptions) -> Result<Self> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| Pattern::new(p).map_err(|e| anyhow!("Invalid pattern {}: {}", p, e)))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            include: compile(&options.include)?,
+            exclude: compile(&options.exclude)?,
+        })
+    }
+
+    fn excludes(&self, relative: &str) -> bool {
+        Self::any_matches(&self.exclude, relative)
+    }
+
+    fn includes_file(&self, relative: &str) -> bool {
+        self.include.is_empty() || Self::any_matches(&self.include, relative)
+    }
+
+    fn any_matches(patterns: &[Pattern], relative: &str) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        patterns
+            .iter()
+            .any(|p| p.matches(relative) || p.matches(name))
+    }
+}
+
+/// One step of a recursive transfer, relative to the copied directory.
+/// Directories come before their contents.
+enum TreeItem {
+    Dir(String),
+    File {
+        path: String,
+        size: u64,
+        modified: u64,
+    },
+    Symlink(String, PathBuf),
+}
+
+/// State shared by the local and remote tree walks.
+struct TreeWalk<'a> {
+    filter: PathFilter,
+    symlinks: SymlinkPolicy,
+    /// Checked for cancellation; dry runs have none.
+    transfer: Option<&'a Transfer>,
+    items: Vec<TreeItem>,
+    /// Resolved directories already entered, so followed links can't loop.
+    visited: HashSet<PathBuf>,
+    /// Directories (`""` for the root) holding something the walk left out,
+    /// such as excluded paths or skipped links.
+    left_out: HashSet<String>,
+}
+
+impl<'a> TreeWalk<'a> {
+    fn new(options: &TreeOptions, transfer: Option<&'a Transfer>) -> Result<Self> {
+        Ok(Self {
+            filter: PathFilter::new(options)?,
+            symlinks: options.symlinks,
+            transfer,
+            items: Vec::new(),
+            visited: HashSet::new(),
+            left_out: HashSet::new(),
+        })
+    }
+
+    /// Records that `dir`, and so every directory above it, holds more than
+    /// the listed items.
+    fn leave_out(&mut self, dir: &str) {
+        let mut dir = dir;
+        while self.left_out.insert(dir.to_string()) && !dir.is_empty() {
+            dir = parent_of(dir);
+        }
+    }
+
+    fn into_entries(mut self) -> Vec<TreeEntry> {
+        // Links are copied, but aren't entries of their own
+        let link_dirs: Vec<String> = self
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                TreeItem::Symlink(path, _) => Some(parent_of(path).to_string()),
+                _ => None,
+            })
+            .collect();
+        for dir in link_dirs {
+            self.leave_out(&dir);
+        }
+        let left_out = self.left_out;
+        self.items
+            .into_iter()
+            .filter_map(|item| match item {
+                TreeItem::Dir(path) => Some(TreeEntry {
+                    has_unlisted: left_out.contains(&path),
+                    path,
+                    is_dir: true,
+                    size: 0,
+                    modified: 0,
+                }),
+                TreeItem::File {
+                    path,
+                    size,
+                    modified,
+                } => Some(TreeEntry {
+                    path,
+                    is_dir: false,
+                    size,
+                    modified,
+                    has_unlisted: false,
+                }),
+                TreeItem::Symlink(..) => None,
+            })
+            .collect()
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.items
+            .iter()
+            .map(|item| match item {
+                TreeItem::File { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
     }
 
+    /// Adds a directory, then drops it again if include patterns left it empty.
+    fn add_dir(
+        &mut self,
+        relative: String,
+        enter: impl FnOnce(&mut Self, &str) -> Result<()>,
+    ) -> Result<()> {
+        let index = self.items.len();
+        self.items.push(TreeItem::Dir(relative.clone()));
+        enter(self, &relative)?;
+        if !self.filter.include.is_empty() && self.items.len() == index + 1 {
+            self.items.pop();
+            self.leave_out(parent_of(&relative));
+        }
+        Ok(())
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        match self.transfer {
+            Some(transfer) => transfer.check_cancelled(),
+            None => Ok(()),
+        }
+    }
+
+    fn walk_remote(&mut self, sftp: &SftpChannel, dir: &Path, relative: &str) -> Result<()> {
+        let entries = read_dir(sftp, dir)?;
+        ensure_unique_names(&entries, dir)?;
+        for (name, stat) in entries {
+            self.check_cancelled()?;
+            let child = join_relative(relative, &name);
+            if self.filter.excludes(&child) {
+                self.leave_out(relative);
+                continue;
+            }
+            let path = dir.join(&name);
+
+            let mut stat = stat;
+            if stat.file_type().is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Skip => {
+                        self.leave_out(relative);
+                        continue;
+                    }
+                    SymlinkPolicy::CopyAsLink => {
+                        if self.filter.includes_file(&child) {
+                            let target = request(sftp, || sftp.readlink(&path))?;
+                            self.items.push(TreeItem::Symlink(child, target));
+                        } else {
+                            self.leave_out(relative);
+                        }
+                        continue;
+                    }
+                    SymlinkPolicy::Follow => match request(sftp, || sftp.stat(&path)) {
+                        Ok(target) => stat = target,
+                        Err(_) => {
+                            self.leave_out(relative);
+                            continue;
+                        }
+                    },
+                }
+            }
+
+            if stat.is_dir() {
+                if self.symlinks == SymlinkPolicy::Follow
+                    && !self.visited.insert(request(sftp, || sftp.realpath(&path))?)
+                {
+                    self.leave_out(relative);
+                    continue;
+                }
+                self.add_dir(child, |walk, relative| {
+                    walk.walk_remote(sftp, &path, relative)
+                })?;
+            } else if stat.is_file() && self.filter.includes_file(&child) {
+                self.items.push(TreeItem::File {
+                    path: child,
+                    size: stat.size.unwrap_or(0),
+                    modified: stat.mtime.unwrap_or(0),
+                });
+            } else {
+                // Filtered out, or a socket, fifo or device, which can't be copied
+                self.leave_out(relative);
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_local(&mut self, dir: &Path, relative: &str) -> Result<()> {
+        let mut names = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+
+        for name in names {
+            self.check_cancelled()?;
+            let child = join_relative(relative, Path::new(&name));
+            if self.filter.excludes(&child) {
+                self.leave_out(relative);
+                continue;
+            }
+            let path = dir.join(&name);
+
+            let mut meta = std::fs::symlink_metadata(&path)?;
+            if meta.file_type().is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Skip => {
+                        self.leave_out(relative);
+                        continue;
+                    }
+                    SymlinkPolicy::CopyAsLink => {
+                        if self.filter.includes_file(&child) {
+                            let target = std::fs::read_link(&path)?;
+                            self.items.push(TreeItem::Symlink(child, target));
+                        } else {
+                            self.leave_out(relative);
+                        }
+                        continue;
+                    }
+                    SymlinkPolicy::Follow => match std::fs::metadata(&path) {
+                        Ok(target) => meta = target,
+                        Err(_) => {
+                            self.leave_out(relative);
+                            continue;
+                        }
+                    },
+                }
+            }
+
+            if meta.is_dir() {
+                if self.symlinks == SymlinkPolicy::Follow
+                    && !self.visited.insert(std::fs::canonicalize(&path)?)
+                {
+                    self.leave_out(relative);
+                    continue;
+                }
+                self.add_dir(child, |walk, relative| walk.walk_local(&path, relative))?;
+            } else if meta.is_file() && self.filter.includes_file(&child) {
+                self.items.push(TreeItem::File {
+                    path: child,
+                    size: meta.len(),
+                    modified: local_mtime(&meta),
+                });
+            } else {
+                self.leave_out(relative);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails if the server listed one name twice. Items are applied in listing
+/// order, so a name listed as a link and then as a directory would have the
+/// directory's contents written through the link.
+fn ensure_unique_names(entries: &[(PathBuf, FileStat)], dir: &Path) -> Result<()> {
+    let mut names = HashSet::new();
+    for (name, _) in entries {
+        if !names.insert(name) {
+            return Err(anyhow!(
+                "The server listed {} twice in {}",
+                name.display(),
+                dir.display()
+            ));
+        }
+    }
     Ok(())
 }
 
-pub async fn upload_file(
+/// Fails if `relative`, or a directory above it, is a link this transfer
+/// created below `root`, so nothing is written outside the copied tree.
+fn ensure_not_through_link(root: &Path, relative: &str, links: &HashSet<String>) -> Result<()> {
+    let mut path = relative;
+    loop {
+        if links.contains(path)
+            && std::fs::symlink_metadata(root.join(path))
+                .is_ok_and(|meta| meta.file_type().is_symlink())
+        {
+            return Err(anyhow!(
+                "Refusing to write {} through the link {}",
+                relative,
+                path
+            ));
+        }
+        if !path.contains('/') {
+            return Ok(());
+        }
+        path = parent_of(path);
+    }
+}
+
+/// The directory part of a `/`-separated relative path, `""` at the top.
+fn parent_of(relative: &str) -> &str {
+    relative.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn join_relative(parent: &str, name: &Path) -> String {
+    let name = name.to_string_lossy();
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// Recursively copies the remote directory `remote_path` into `local_path`,
+/// which is created if needed. Progress covers the bytes of every file.
+pub async fn download_directory(
     connection: &SshConnection,
-    local_path: &str,
     remote_path: &str,
+    local_path: &str,
+    options: TreeOptions,
+    transfer: &Transfer,
 ) -> Result<()> {
-    let session_arc = connection.get_session().await;
-    let session_guard = session_arc.lock().await;
+    let remote_root = Path::new(remote_path).to_path_buf();
+    let local_root = Path::new(local_path).to_path_buf();
+    let transfer = transfer.clone();
 
-    let session = session_guard
-        .as_ref()
-        .ok_or_else(|| anyhow!("No active session"))?;
+    with_sftp(connection, move |sftp| {
+        let mut walk = TreeWalk::new(&options, Some(&transfer))?;
+        walk.visited.insert(request_at(sftp, &remote_root, || {
+            sftp.realpath(&remote_root)
+        })?);
+        walk.walk_remote(sftp, &remote_root, "")?;
+        transfer.set_total(walk.total_bytes());
 
-    let sftp = session.sftp()?;
+        std::fs::create_dir_all(&local_root)?;
+        let mut links = HashSet::new();
+        for item in &walk.items {
+            transfer.check_cancelled()?;
+            match item {
+                TreeItem::Dir(relative) => {
+                    ensure_not_through_link(&local_root, relative, &links)?;
+                    std::fs::create_dir_all(local_root.join(relative))?
+                }
+                TreeItem::File { path: relative, .. } => {
+                    ensure_not_through_link(&local_root, relative, &links)?;
+                    download_one(
+                        sftp,
+                        &remote_root.join(relative),
+                        &local_root.join(relative),
+                        &transfer,
+                    )?;
+                }
+                TreeItem::Symlink(relative, target) => {
+                    ensure_not_through_link(&local_root, parent_of(relative), &links)?;
+                    create_local_symlink(target, &local_root.join(relative))?;
+                    links.insert(relative.clone());
+                }
+            }
+        }
+        Ok(())
+    })
+    .await
+}
 
-    let mut local_file = std::fs::File::open(local_path)?;
-    let mut remote_file = sftp.create(Path::new(remote_path))?;
+/// Recursively copies the local directory `local_path` into `remote_path`,
+/// which is created if needed. Progress covers the bytes of every file.
+pub async fn upload_directory(
+    connection: &SshConnection,
+    local_path: &str,
+    remote_path: &str,
+    options: TreeOptions,
+    transfer: &Transfer,
+) -> Result<()> {
+    let local_root = Path::new(local_path).to_path_buf();
+    let remote_root = Path::new(remote_path).to_path_buf();
+    let transfer = transfer.clone();
 
-    let mut buffer = vec![0; 8192];
-    loop {
-        let n = local_file.read(&mut buffer)?;
-        if n == 0 {
-            break;
+    with_sftp(connection, move |sftp| {
+        let mut walk = TreeWalk::new(&options, Some(&transfer))?;
+        walk.visited.insert(std::fs::canonicalize(&local_root)?);
+        walk.walk_local(&local_root, "")?;
+        transfer.set_total(walk.total_bytes());
+
+        ensure_remote_dir(sftp, &remote_root)?;
+        for item in &walk.items {
+            transfer.check_cancelled()?;
+            match item {
+                TreeItem::Dir(relative) => ensure_remote_dir(sftp, &remote_root.join(relative))?,
+                TreeItem::File { path: relative, .. } => {
+                    upload_one(
+                        sftp,
+                        &local_root.join(relative),
+                        &remote_root.join(relative),
+                        &transfer,
+                    )?;
+                }
+                TreeItem::Symlink(relative, target) => {
+                    let link = remote_root.join(relative);
+                    if request(sftp, || sftp.lstat(&link))
+                        .is_ok_and(|stat| stat.file_type().is_symlink())
+                    {
+                        request(sftp, || sftp.unlink(&link))?;
+                    }
+                    // ssh2 takes the link's target first, then the link itself
+                    request(sftp, || sftp.symlink(target, &link))?;
+                }
+            }
         }
-        remote_file.write_all(&buffer[..n])?;
+        Ok(())
+    })
+    .await
+}
+
+fn ensure_remote_dir(sftp: &SftpChannel, path: &Path) -> Result<()> {
+    match request(sftp, || sftp.stat(path)) {
+        Ok(stat) if stat.is_dir() => Ok(()),
+        Ok(_) => Err(anyhow!("{} exists and is not a directory", path.display())),
+        Err(_) => request_at(sftp, path, || sftp.mkdir(path, 0o755)),
     }
+}
 
+#[cfg(unix)]
+fn create_local_symlink(target: &Path, link: &Path) -> Result<()> {
+    if std::fs::symlink_metadata(link).is_ok_and(|meta| meta.file_type().is_symlink()) {
+        std::fs::remove_file(link)?;
+    }
+    std::os::unix::fs::symlink(target, link)?;
     Ok(())
 }
+
+// Creating links on Windows needs extra privileges, so they are left out
+#[cfg(not(unix))]
+fn create_local_symlink(_target: &Path, _link: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// A file or directory found by `scan_remote_tree` or `scan_local_tree`.
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    /// Relative to the scanned directory, with `/` separators.
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Unix timestamp; 0 for directories.
+    pub modified: u64,
+    /// For directories: something below was left out of the scan, so
+    /// removing the listed entries won't empty it.
+    pub has_unlisted: bool,
+}
+
+/// Lists everything below the remote directory `root` that `options` selects.
+/// A missing `root` gives an empty list.
+pub async fn scan_remote_tree(
+    connection: &SshConnection,
+    root: &str,
+    options: TreeOptions,
+) -> Result<Vec<TreeEntry>> {
+    let root = Path::new(root).to_path_buf();
+
+    with_sftp(connection, move |sftp| {
+        let Ok(real_root) = request(sftp, || sftp.realpath(&root)) else {
+            return Ok(Vec::new());
+        };
+        let mut walk = TreeWalk::new(&options, None)?;
+        walk.visited.insert(real_root);
+        walk.walk_remote(sftp, &root, "")?;
+        Ok(walk.into_entries())
+    })
+    .await
+}
+
+/// Lists everything below the local directory `root` that `options` selects.
+/// A missing `root` gives an empty list.
+pub fn scan_local_tree(root: &str, options: &TreeOptions) -> Result<Vec<TreeEntry>> {
+    let root = Path::new(root);
+    let Ok(real_root) = std::fs::canonicalize(root) else {
+        return Ok(Vec::new());
+    };
+    let mut walk = TreeWalk::new(options, None)?;
+    walk.visited.insert(real_root);
+    walk.walk_local(root, "")?;
+    Ok(walk.into_entries())
+}
+
+/// Downloads one file as part of a larger transfer, whose total the caller
+/// sets. The local copy gets the remote modification time, unless the
+/// conflict policy skipped it.
+pub async fn download_part(
+    connection: &SshConnection,
+    remote_path: &str,
+    local_path: &str,
+    transfer: &Transfer,
+) -> Result<()> {
+    let remote_path = Path::new(remote_path).to_path_buf();
+    let local_path = Path::new(local_path).to_path_buf();
+    let transfer = transfer.clone();
+
+    with_sftp(connection, move |sftp| {
+        let Some(written) = download_one(sftp, &remote_path, &local_path, &transfer)? else {
+            return Ok(());
+        };
+        if let Some(mtime) = request_at(sftp, &remote_path, || sftp.stat(&remote_path))?.mtime {
+            let modified = std::time::UNIX_EPOCH + Duration::from_secs(mtime);
+            std::fs::File::options()
+                .write(true)
+                .open(&written)?
+                .set_modified(modified)?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Uploads one file as part of a larger transfer, whose total the caller
+/// sets. The remote copy gets the local modification time, unless the
+/// conflict policy skipped it.
+pub async fn upload_part(
+    connection: &SshConnection,
+    local_path: &str,
+    remote_path: &str,
+    transfer: &Transfer,
+) -> Result<()> {
+    let local_path = Path::new(local_path).to_path_buf();
+    let remote_path = Path::new(remote_path).to_path_buf();
+    let transfer = transfer.clone();
+
+    with_sftp(connection, move |sftp| {
+        let Some(written) = upload_one(sftp, &local_path, &remote_path, &transfer)? else {
+            return Ok(());
+        };
+        let mtime = local_mtime(&std::fs::metadata(&local_path)?);
+        request_at(sftp, &written, || {
+            sftp.setstat(
+                &written,
+                stat_with(|s| {
+                    s.atime = Some(mtime);
+                    s.mtime = Some(mtime);
+                }),
+            )
+        })
+    })
+    .await
+}
+
+/// Hex SHA-256 of a remote file. Uses `sha256sum` on the host when it can, so
+/// the file doesn't have to cross the network, and reads it over SFTP otherwise.
+pub async fn hash_remote_file(connection: &SshConnection, path: &str) -> Result<String> {
+    let command = format!("sha256sum -- {}", shell_quote(path));
+    if let Ok(output) = remote::exec(connection, &command, None).await {
+        let digest = output.stdout.split_whitespace().next().filter(|hex| {
+            output.success() && hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())
+        });
+        if let Some(digest) = digest {
+            return Ok(digest.to_lowercase());
+        }
+    }
+
+    let path = Path::new(path).to_path_buf();
+    with_sftp(connection, move |sftp| {
+        let mut file = request_at(sftp, &path, || sftp.open(&path))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let n = request_io(sftp, || file.read(&mut buffer))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        request(sftp, || file.close())?;
+        Ok(hex_digest(&hasher.finalize()))
+    })
+    .await
+}
+
+/// Hex SHA-256 of a local file.
+pub fn hash_local_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex_digest(&hasher.finalize()))
+}
+
+pub(crate) fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Modification time as a Unix timestamp, 0 if the platform has none.
+fn local_mtime(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_names_are_single_components() {
+        for name in ["file.txt", ".bashrc", "...", "a b"] {
+            assert!(is_plain_name(Path::new(name)), "{name}");
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            "../../.zshrc",
+            "/Users/x/.ssh/authorized_keys",
+            "dir/file",
+            "dir/",
+        ] {
+            assert!(!is_plain_name(Path::new(name)), "{name}");
+        }
+    }
+
+    #[test]
+    fn repeated_names_are_rejected() {
+        let stat = || FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None,
+        };
+        let dir = Path::new("/srv");
+        let mut entries = vec![(PathBuf::from("d"), stat()), (PathBuf::from("e"), stat())];
+        assert!(ensure_unique_names(&entries, dir).is_ok());
+        entries.push((PathBuf::from("d"), stat()));
+        assert!(ensure_unique_names(&entries, dir).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_created_links_are_refused() {
+        let root = std::env::temp_dir().join(format!("gterm-sftp-links-{}", std::process::id()));
+        let outside = root.with_extension("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        create_local_symlink(&outside, &root.join("d")).unwrap();
+        std::fs::create_dir_all(root.join("e")).unwrap();
+
+        let links = HashSet::from(["d".to_string()]);
+        assert!(ensure_not_through_link(&root, "d", &links).is_err());
+        assert!(ensure_not_through_link(&root, "d/authorized_keys", &links).is_err());
+        assert!(ensure_not_through_link(&root, "d/sub/file", &links).is_err());
+        assert!(ensure_not_through_link(&root, "e/file", &links).is_ok());
+        assert!(ensure_not_through_link(&root, "", &links).is_ok());
+        // A link that was already there isn't one this transfer made
+        assert!(ensure_not_through_link(&root, "d/file", &HashSet::new()).is_ok());
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn permissions_text_matches_ls() {
+        assert_eq!(permissions_text(FileKind::File, 0o644), "-rw-r--r--");
+        assert_eq!(permissions_text(FileKind::Directory, 0o755), "drwxr-xr-x");
+        assert_eq!(permissions_text(FileKind::Symlink, 0o777), "lrwxrwxrwx");
+        assert_eq!(permissions_text(FileKind::File, 0o4755), "-rwsr-xr-x");
+        assert_eq!(permissions_text(FileKind::File, 0o2644), "-rw-r-Sr--");
+        assert_eq!(permissions_text(FileKind::Directory, 0o1777), "drwxrwxrwt");
+        assert_eq!(permissions_text(FileKind::Directory, 0o1770), "drwxrwx--T");
+        assert_eq!(permissions_text(FileKind::Fifo, 0), "p---------");
+    }
+
+    #[test]
+    fn left_out_entries_mark_every_parent() {
+        let mut walk = TreeWalk::new(&TreeOptions::default(), None).unwrap();
+        walk.items = vec![
+            TreeItem::Dir("a".into()),
+            TreeItem::Dir("a/b".into()),
+            TreeItem::Dir("c".into()),
+            TreeItem::Dir("d".into()),
+            TreeItem::Symlink("d/link".into(), PathBuf::from("target")),
+        ];
+        walk.leave_out("a/b");
+
+        let unlisted: Vec<_> = walk
+            .into_entries()
+            .into_iter()
+            .filter(|entry| entry.has_unlisted)
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(unlisted, ["a", "a/b", "d"]);
+    }
+}
@@ -0,0 +1,391 @@
+//! Synchronizes a local directory with a remote one. Both trees are compared
+//! by size and modification time, optionally by content, into a plan that can
+//! be reviewed as a dry run before it is carried out.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tauri::Manager;
+
+use super::connection::SshConnection;
+use super::sftp::{self, SymlinkPolicy, TreeEntry, TreeOptions};
+use super::transfer::Transfer;
+
+/// Modification times closer than this count as equal; FAT volumes and some
+/// servers keep only even seconds.
+const MTIME_TOLERANCE: u64 = 2;
+
+const PROFILES_FILE: &str = "sync-profiles.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    /// Copy new and changed local files to the remote side.
+    #[default]
+    Upload,
+    /// Copy new and changed remote files to the local side.
+    Download,
+    /// Like `Upload`, and delete remote files that don't exist locally.
+    MirrorToRemote,
+    /// Like `Download`, and delete local files that don't exist remotely.
+    MirrorToLocal,
+    /// Copy both ways, the newer file winning. Nothing is deleted, since a
+    /// file missing on one side can't be told apart from a new one.
+    TwoWay,
+}
+
+/// A saved pairing of a local and a remote directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncProfile {
+    pub name: String,
+    pub local_path: String,
+    pub remote_path: String,
+    #[serde(default)]
+    pub mode: SyncMode,
+    /// Glob patterns as for recursive transfers. Excluded paths are neither
+    /// copied nor deleted.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// When sizes match but times differ, compare contents instead of
+    /// assuming the file changed.
+    #[serde(default)]
+    pub compare_hashes: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncAction {
+    CreateRemoteDir,
+    CreateLocalDir,
+    Upload,
+    Download,
+    DeleteRemote,
+    DeleteLocal,
+    /// Reported only; running the plan leaves the path alone.
+    Conflict,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncStep {
+    pub action: SyncAction,
+    /// Relative to both roots, with `/` separators.
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncPlan {
+    pub steps: Vec<SyncStep>,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+}
+
+impl SyncPlan {
+    fn push(&mut self, action: SyncAction, entry: &TreeEntry, reason: &str) {
+        match action {
+            SyncAction::Upload => self.upload_bytes += entry.size,
+            SyncAction::Download => self.download_bytes += entry.size,
+            _ => {}
+        }
+        self.steps.push(SyncStep {
+            action,
+            path: entry.path.clone(),
+            is_dir: entry.is_dir,
+            size: if entry.is_dir { 0 } else { entry.size },
+            reason: reason.to_string(),
+        });
+    }
+}
+
+/// Compares both trees and lists what running the profile would do.
+pub async fn plan(connection: &SshConnection, profile: &SyncProfile) -> Result<SyncPlan> {
+    let options = TreeOptions {
+        symlinks: SymlinkPolicy::Skip,
+        exclude: profile.exclude.clone(),
+        ..Default::default()
+    };
+    let remote = sftp::scan_remote_tree(connection, &profile.remote_path, options.clone()).await?;
+    let local = {
+        let root = profile.local_path.clone();
+        tokio::task::spawn_blocking(move || sftp::scan_local_tree(&root, &options)).await??
+    };
+    let local: BTreeMap<_, _> = local.into_iter().map(|e| (e.path.clone(), e)).collect();
+    let remote: BTreeMap<_, _> = remote.into_iter().map(|e| (e.path.clone(), e)).collect();
+    let paths: BTreeSet<_> = local.keys().chain(remote.keys()).cloned().collect();
+
+    let mode = profile.mode;
+    let uploads = matches!(
+        mode,
+        SyncMode::Upload | SyncMode::MirrorToRemote | SyncMode::TwoWay
+    );
+    let downloads = matches!(
+        mode,
+        SyncMode::Download | SyncMode::MirrorToLocal | SyncMode::TwoWay
+    );
+
+    let mut plan = SyncPlan::default();
+    // Directories whose contents need no steps of their own, since they clash
+    // with a file
+    let mut skipped: Vec<String> = Vec::new();
+    // Deleted one entry at a time, so excluded paths inside survive; listed
+    // parents first here and reversed at the end
+    let mut deletes: Vec<(SyncAction, &TreeEntry, &str)> = Vec::new();
+
+    for path in paths {
+        if skipped
+            .iter()
+            .any(|dir| path.starts_with(&format!("{}/", dir)))
+        {
+            continue;
+        }
+        match (local.get(&path), remote.get(&path)) {
+            (Some(local), None) => {
+                if uploads {
+                    let action = if local.is_dir {
+                        SyncAction::CreateRemoteDir
+                    } else {
+                        SyncAction::Upload
+                    };
+                    plan.push(action, local, "missing remotely");
+                } else if mode == SyncMode::MirrorToLocal && !local.has_unlisted {
+                    deletes.push((SyncAction::DeleteLocal, local, "not on the remote side"));
+                }
+            }
+            (None, Some(remote)) => {
+                if downloads {
+                    let action = if remote.is_dir {
+                        SyncAction::CreateLocalDir
+                    } else {
+                        SyncAction::Download
+                    };
+                    plan.push(action, remote, "missing locally");
+                } else if mode == SyncMode::MirrorToRemote && !remote.has_unlisted {
+                    deletes.push((SyncAction::DeleteRemote, remote, "not on the local side"));
+                }
+            }
+            (Some(local), Some(remote)) if local.is_dir != remote.is_dir => {
+                plan.push(
+                    SyncAction::Conflict,
+                    local,
+                    "a file on one side and a directory on the other",
+                );
+                skipped.push(path);
+            }
+            (Some(local), Some(remote)) if !local.is_dir => {
+                if same_file(connection, profile, local, remote).await? {
+                    continue;
+                }
+                let local_newer = local.modified > remote.modified + MTIME_TOLERANCE;
+                let remote_newer = remote.modified > local.modified + MTIME_TOLERANCE;
+                match mode {
+                    SyncMode::Upload | SyncMode::MirrorToRemote => {
+                        plan.push(SyncAction::Upload, local, "changed")
+                    }
+                    SyncMode::Download | SyncMode::MirrorToLocal => {
+                        plan.push(SyncAction::Download, remote, "changed")
+                    }
+                    SyncMode::TwoWay if local_newer => {
+                        plan.push(SyncAction::Upload, local, "newer locally")
+                    }
+                    SyncMode::TwoWay if remote_newer => {
+                        plan.push(SyncAction::Download, remote, "newer remotely")
+                    }
+                    SyncMode::TwoWay => plan.push(
+                        SyncAction::Conflict,
+                        local,
+                        "different contents with the same modification time",
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+    for (action, entry, reason) in deletes.into_iter().rev() {
+        plan.push(action, entry, reason);
+    }
+    Ok(plan)
+}
+
+async fn same_file(
+    connection: &SshConnection,
+    profile: &SyncProfile,
+    local: &TreeEntry,
+    remote: &TreeEntry,
+) -> Result<bool> {
+    if local.size != remote.size {
+        return Ok(false);
+    }
+    if local.modified.abs_diff(remote.modified) <= MTIME_TOLERANCE {
+        return Ok(true);
+    }
+    if !profile.compare_hashes {
+        return Ok(false);
+    }
+
+    let local_path = local_path(profile, &local.path)?;
+    let local_hash =
+        tokio::task::spawn_blocking(move || sftp::hash_local_file(&local_path)).await??;
+    let remote_hash =
+        sftp::hash_remote_file(connection, &remote_path(profile, &remote.path)?).await?;
+    Ok(local_hash == remote_hash)
+}
+
+/// Carries out `plan`, reporting the copied bytes through `transfer`. Copied
+/// files keep their modification times, so the next plan sees them as equal.
+pub async fn run(
+    connection: &SshConnection,
+    profile: &SyncProfile,
+    plan: &SyncPlan,
+    transfer: &Transfer,
+) -> Result<()> {
+    transfer.set_total(plan.upload_bytes + plan.download_bytes);
+
+    for step in &plan.steps {
+        transfer.check_cancelled()?;
+        let local = local_path(profile, &step.path)?;
+        let remote = remote_path(profile, &step.path)?;
+        // Directories are removed only once empty: their contents have steps
+        // of their own, and excluded paths inside must survive
+        match step.action {
+            SyncAction::CreateRemoteDir => sftp::create_directory(connection, &remote).await?,
+            SyncAction::CreateLocalDir => blocking(move || std::fs::create_dir_all(local)).await?,
+            SyncAction::Upload => {
+                sftp::upload_part(connection, &local.to_string_lossy(), &remote, transfer).await?
+            }
+            SyncAction::Download => {
+                if let Some(parent) = local.parent().map(Path::to_path_buf) {
+                    blocking(move || std::fs::create_dir_all(parent)).await?;
+                }
+                sftp::download_part(connection, &remote, &local.to_string_lossy(), transfer).await?
+            }
+            SyncAction::DeleteRemote => sftp::remove(connection, &remote, false).await?,
+            SyncAction::DeleteLocal if step.is_dir => {
+                blocking(move || std::fs::remove_dir(local)).await?
+            }
+            SyncAction::DeleteLocal => blocking(move || std::fs::remove_file(local)).await?,
+            SyncAction::Conflict => {}
+        }
+    }
+    Ok(())
+}
+
+/// Runs a local filesystem call off the async runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> Result<T> {
+    Ok(tokio::task::spawn_blocking(f).await??)
+}
+
+fn local_path(profile: &SyncProfile, relative: &str) -> Result<PathBuf> {
+    check_relative(relative)?;
+    Ok(Path::new(&profile.local_path).join(relative))
+}
+
+fn remote_path(profile: &SyncProfile, relative: &str) -> Result<String> {
+    check_relative(relative)?;
+    Ok(format!(
+        "{}/{}",
+        profile.remote_path.trim_end_matches('/'),
+        relative
+    ))
+}
+
+/// Plans can come back from the frontend, so a step's path is checked to stay
+/// below the profile's directories before anything is written or deleted.
+fn check_relative(relative: &str) -> Result<()> {
+    if relative
+        .split('/')
+        .all(|part| sftp::is_plain_name(Path::new(part)))
+    {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Refusing to sync {:?}: not a path below the synced directories",
+            relative
+        ))
+    }
+}
+
+/// Saved profiles by host id, kept next to the frontend's stores.
+fn profiles_path(app_handle: &tauri::AppHandle) -> Result<PathBuf> {
+    Ok(app_handle.path().app_data_dir()?.join(PROFILES_FILE))
+}
+
+fn read_profiles(app_handle: &tauri::AppHandle) -> Result<BTreeMap<String, Vec<SyncProfile>>> {
+    let path = profiles_path(app_handle)?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    serde_json::from_str(&contents)
+        .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+}
+
+fn write_profiles(
+    app_handle: &tauri::AppHandle,
+    profiles: &BTreeMap<String, Vec<SyncProfile>>,
+) -> Result<()> {
+    let path = profiles_path(app_handle)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(profiles)?)?;
+    Ok(())
+}
+
+pub fn profiles(app_handle: &tauri::AppHandle, host_id: &str) -> Result<Vec<SyncProfile>> {
+    Ok(read_profiles(app_handle)?
+        .remove(host_id)
+        .unwrap_or_default())
+}
+
+/// Adds `profile` to the host's list, replacing one with the same name.
+pub fn save_profile(
+    app_handle: &tauri::AppHandle,
+    host_id: &str,
+    profile: SyncProfile,
+) -> Result<Vec<SyncProfile>> {
+    let mut all = read_profiles(app_handle)?;
+    let list = all.entry(host_id.to_string()).or_default();
+    match list.iter_mut().find(|p| p.name == profile.name) {
+        Some(existing) => *existing = profile,
+        None => list.push(profile),
+    }
+    let list = list.clone();
+    write_profiles(app_handle, &all)?;
+    Ok(list)
+}
+
+pub fn delete_profile(
+    app_handle: &tauri::AppHandle,
+    host_id: &str,
+    name: &str,
+) -> Result<Vec<SyncProfile>> {
+    let mut all = read_profiles(app_handle)?;
+    let list = all.entry(host_id.to_string()).or_default();
+    list.retain(|p| p.name != name);
+    let list = list.clone();
+    if list.is_empty() {
+        all.remove(host_id);
+    }
+    write_profiles(app_handle, &all)?;
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_paths_must_stay_below_the_roots() {
+        assert!(check_relative("a/b/c.txt").is_ok());
+        assert!(check_relative("../outside").is_err());
+        assert!(check_relative("a/../../outside").is_err());
+        assert!(check_relative("/etc/passwd").is_err());
+        assert!(check_relative("a//b").is_err());
+        assert!(check_relative("").is_err());
+    }
+}
@@ -0,0 +1,532 @@
+//! Bookkeeping for SFTP transfers: ids, progress events, cancellation and
+//! existing destination files.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tokio::sync::Mutex;
+
+use super::prompt::PromptBroker;
+
+/// Minimum gap between two progress events for the same transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+const UNKNOWN_TOTAL: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransferDirection {
+    Upload,
+    Download,
+    /// A sync plan, which may copy both ways.
+    Sync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransferState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Payload of the `sftp-progress:{session_id}` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub transfer_id: String,
+    pub direction: TransferDirection,
+    pub source: String,
+    pub destination: String,
+    pub bytes_done: u64,
+    pub total_bytes: Option<u64>,
+    /// Part of `bytes_done` that was already at the destination when the
+    /// transfer resumed.
+    pub resumed_bytes: u64,
+    /// Average since the transfer started, not counting resumed bytes.
+    pub bytes_per_sec: f64,
+    pub state: TransferState,
+    pub error: Option<String>,
+}
+
+/// What to do when a file's destination already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    #[default]
+    Overwrite,
+    Skip,
+    /// Copy to a free name such as `report (1).txt` instead.
+    Rename,
+    /// Overwrite only if the source was modified later than the destination.
+    OverwriteIfNewer,
+    OverwriteIfSizeDiffers,
+    /// Emit `sftp-conflict:{session_id}` and wait for an answer.
+    Ask,
+}
+
+/// What happened to one file of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileAction {
+    Created,
+    Overwritten,
+    /// Continued from a partial destination file.
+    Resumed,
+    /// Copied under a new name because the destination existed.
+    Renamed,
+    Skipped,
+}
+
+/// Payload of the `sftp-file:{session_id}` event, sent once per file.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub transfer_id: String,
+    pub source: String,
+    /// Where the file went; differs from the requested path when renamed.
+    pub destination: String,
+    pub action: FileAction,
+}
+
+/// Size and Unix modification time of one side of a conflict.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FileVersion {
+    pub size: u64,
+    pub modified: u64,
+}
+
+/// Payload of the `sftp-conflict:{session_id}` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConflictRequest {
+    pub prompt_id: String,
+    pub transfer_id: String,
+    pub source: String,
+    pub destination: String,
+    pub source_version: FileVersion,
+    pub destination_version: FileVersion,
+}
+
+/// The user's choice for a conflict. With `apply_to_all`, the rest of the
+/// transfer uses the same policy without asking again.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ConflictAnswer {
+    pub policy: ConflictPolicy,
+    #[serde(default)]
+    pub apply_to_all: bool,
+}
+
+/// Per-transfer settings passed in by the frontend.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferOptions {
+    /// Continue from any destination file shorter than its source instead of
+    /// starting over. When unset, only the file a cancelled or failed
+    /// transfer was writing is continued, when the transfer is retried under
+    /// the same id.
+    pub resume: Option<bool>,
+    /// Before resuming, compare checksums of the end of the part already
+    /// copied, and start over if source and destination differ.
+    pub verify_resume: bool,
+    /// Applies to every file of the transfer whose destination exists and
+    /// isn't a partial copy being resumed.
+    pub conflict: ConflictPolicy,
+}
+
+/// Returned by copy loops once `sftp_cancel` has been called for them.
+#[derive(Debug, thiserror::Error)]
+#[error("Transfer cancelled")]
+pub struct TransferCancelled;
+
+struct TransferInner {
+    id: String,
+    session_id: String,
+    direction: TransferDirection,
+    source: String,
+    destination: String,
+    app_handle: tauri::AppHandle,
+    prompts: PromptBroker,
+    started: Instant,
+    cancelled: AtomicBool,
+    /// Set by `sftp_cancel` to leave the partial file for a later resume.
+    keep_partial: AtomicBool,
+    /// `TransferOptions::resume`.
+    resume: std::sync::Mutex<Option<bool>>,
+    /// The partial file an earlier, interrupted run of this transfer left.
+    resume_path: std::sync::Mutex<Option<PathBuf>>,
+    /// Destination currently being written, until its copy completes.
+    in_flight: std::sync::Mutex<Option<PathBuf>>,
+    verify_resume: AtomicBool,
+    conflict: std::sync::Mutex<ConflictPolicy>,
+    bytes_done: AtomicU64,
+    resumed_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    /// Milliseconds after `started` of the last progress event.
+    last_emit_ms: AtomicU64,
+}
+
+/// A running transfer, shared between the copy loop and the command that
+/// started it.
+#[derive(Clone)]
+pub struct Transfer(Arc<TransferInner>);
+
+impl Transfer {
+    pub fn id(&self) -> &str {
+        &self.0.id
+    }
+
+    pub fn set_total(&self, total_bytes: u64) {
+        self.0.total_bytes.store(total_bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_options(&self, options: &TransferOptions) {
+        *self.0.resume.lock().unwrap() = options.resume;
+        self.0
+            .verify_resume
+            .store(options.verify_resume, Ordering::Relaxed);
+        *self.0.conflict.lock().unwrap() = options.conflict;
+    }
+
+    /// Whether a destination shorter than its source is continued rather than
+    /// treated as an existing file.
+    pub fn resumes_into(&self, destination: &Path) -> bool {
+        match *self.0.resume.lock().unwrap() {
+            Some(resume) => resume,
+            None => self.0.resume_path.lock().unwrap().as_deref() == Some(destination),
+        }
+    }
+
+    /// Sets the partial file left by an earlier run, such as one recorded in
+    /// the transfer queue before a restart. It counts as in flight until it
+    /// has been continued, so it isn't forgotten if this run fails first.
+    pub fn set_resume_path(&self, path: Option<PathBuf>) {
+        *self.0.in_flight.lock().unwrap() = path.clone();
+        *self.0.resume_path.lock().unwrap() = path;
+    }
+
+    /// The destination being written right now; after a failure or a
+    /// cancellation that kept it, the partial file a retry can continue.
+    pub fn in_flight(&self) -> Option<PathBuf> {
+        self.0.in_flight.lock().unwrap().clone()
+    }
+
+    /// Called by the copy loops around each file they write.
+    pub fn set_in_flight(&self, path: Option<&Path>) {
+        *self.0.in_flight.lock().unwrap() = path.map(Path::to_path_buf);
+    }
+
+    pub fn verifies_resume(&self) -> bool {
+        self.0.verify_resume.load(Ordering::Relaxed)
+    }
+
+    /// Whether a cancelled copy should leave its partial file in place.
+    pub fn keeps_partial(&self) -> bool {
+        self.0.keep_partial.load(Ordering::Relaxed)
+    }
+
+    /// Counts bytes that were already at the destination as done.
+    pub fn skip(&self, bytes: u64) {
+        self.0.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        self.0.resumed_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.0.cancelled.load(Ordering::Relaxed) {
+            Err(TransferCancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Decides where a file copied from `source` goes when `destination` may
+    /// already exist, asking the user if the policy says so. Returns the path
+    /// to write and what writing it counts as (`Resumed` only until the copy
+    /// finds out whether the partial file can be continued), or `None` once a
+    /// skipped file has been reported. Runs on the copy thread; `exists`
+    /// checks whether a path on the destination side is taken.
+    pub fn resolve_conflict(
+        &self,
+        source: &Path,
+        source_version: FileVersion,
+        destination: &Path,
+        existing: Option<FileVersion>,
+        exists: impl Fn(&Path) -> bool,
+    ) -> Result<Option<(PathBuf, FileAction)>> {
+        let Some(existing) = existing else {
+            return Ok(Some((destination.to_path_buf(), FileAction::Created)));
+        };
+        // A shorter destination is the partial copy a resume continues
+        if self.resumes_into(destination) && existing.size < source_version.size {
+            return Ok(Some((destination.to_path_buf(), FileAction::Resumed)));
+        }
+
+        let mut policy = *self.0.conflict.lock().unwrap();
+        if policy == ConflictPolicy::Ask {
+            policy = self.ask(source, source_version, destination, existing)?;
+        }
+        let overwrite = match policy {
+            ConflictPolicy::Overwrite => true,
+            ConflictPolicy::OverwriteIfNewer => source_version.modified > existing.modified,
+            ConflictPolicy::OverwriteIfSizeDiffers => source_version.size != existing.size,
+            ConflictPolicy::Rename => {
+                let renamed = free_name(destination, exists);
+                return Ok(Some((renamed, FileAction::Renamed)));
+            }
+            ConflictPolicy::Skip | ConflictPolicy::Ask => false,
+        };
+        if overwrite {
+            return Ok(Some((destination.to_path_buf(), FileAction::Overwritten)));
+        }
+
+        self.skip(source_version.size);
+        self.report(source, destination, FileAction::Skipped);
+        Ok(None)
+    }
+
+    fn ask(
+        &self,
+        source: &Path,
+        source_version: FileVersion,
+        destination: &Path,
+        destination_version: FileVersion,
+    ) -> Result<ConflictPolicy> {
+        let inner = &self.0;
+        let event = format!("sftp-conflict:{}", inner.session_id);
+        let answer: Option<ConflictAnswer> = tauri::async_runtime::block_on(inner.prompts.ask(
+            &inner.app_handle,
+            &event,
+            |prompt_id| ConflictRequest {
+                prompt_id,
+                transfer_id: inner.id.clone(),
+                source: source.display().to_string(),
+                destination: destination.display().to_string(),
+                source_version,
+                destination_version,
+            },
+        ));
+
+        // Dismissing the prompt stops the whole transfer
+        let answer = answer
+            .filter(|answer| answer.policy != ConflictPolicy::Ask)
+            .ok_or(TransferCancelled)?;
+        if answer.apply_to_all {
+            *inner.conflict.lock().unwrap() = answer.policy;
+        }
+        self.check_cancelled()?;
+        Ok(answer.policy)
+    }
+
+    /// Emits the `sftp-file` event for one finished file.
+    pub fn report(&self, source: &Path, destination: &Path, action: FileAction) {
+        let inner = &self.0;
+        let _ = inner.app_handle.emit(
+            &format!("sftp-file:{}", inner.session_id),
+            FileReport {
+                transfer_id: inner.id.clone(),
+                source: source.display().to_string(),
+                destination: destination.display().to_string(),
+                action,
+            },
+        );
+    }
+
+    /// Records copied bytes, emitting progress at most every `PROGRESS_INTERVAL`.
+    /// Fails with `TransferCancelled` once the transfer has been cancelled.
+    pub fn advance(&self, bytes: usize) -> Result<()> {
+        self.0.bytes_done.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        let now_ms = self.0.started.elapsed().as_millis() as u64;
+        let last_ms = self.0.last_emit_ms.load(Ordering::Relaxed);
+        if now_ms.saturating_sub(last_ms) >= PROGRESS_INTERVAL.as_millis() as u64
+            && self
+                .0
+                .last_emit_ms
+                .compare_exchange(last_ms, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.emit(TransferState::Running, None);
+        }
+        self.check_cancelled()
+    }
+
+    fn finish(&self, result: &Result<()>) {
+        match result {
+            Ok(()) => self.emit(TransferState::Completed, None),
+            Err(e) if e.is::<TransferCancelled>() => self.emit(TransferState::Cancelled, None),
+            Err(e) => self.emit(TransferState::Failed, Some(e.to_string())),
+        }
+    }
+
+    fn emit(&self, state: TransferState, error: Option<String>) {
+        let inner = &self.0;
+        let bytes_done = inner.bytes_done.load(Ordering::Relaxed);
+        let resumed_bytes = inner.resumed_bytes.load(Ordering::Relaxed);
+        let total_bytes =
+            Some(inner.total_bytes.load(Ordering::Relaxed)).filter(|&total| total != UNKNOWN_TOTAL);
+        let elapsed = inner.started.elapsed().as_secs_f64();
+
+        let _ = inner.app_handle.emit(
+            &format!("sftp-progress:{}", inner.session_id),
+            TransferProgress {
+                transfer_id: inner.id.clone(),
+                direction: inner.direction,
+                source: inner.source.clone(),
+                destination: inner.destination.clone(),
+                bytes_done,
+                total_bytes,
+                resumed_bytes,
+                bytes_per_sec: if elapsed > 0.0 {
+                    (bytes_done - resumed_bytes) as f64 / elapsed
+                } else {
+                    0.0
+                },
+                state,
+                error,
+            },
+        );
+    }
+}
+
+/// `path` with ` (1)`, ` (2)`, … added before the extension, whichever is
+/// free first.
+fn free_name(path: &Path, exists: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !exists(candidate))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Tracks running transfers so they can be cancelled by id.
+#[derive(Clone, Default)]
+pub struct TransferManager {
+    active: Arc<Mutex<HashMap<String, Transfer>>>,
+    /// Transfers that were cancelled or failed, with the partial file each
+    /// left behind; retrying one continues that file.
+    interrupted: Arc<Mutex<HashMap<String, Option<PathBuf>>>>,
+    next_id: Arc<AtomicU64>,
+    prompts: PromptBroker,
+}
+
+impl TransferManager {
+    pub fn new(prompts: PromptBroker) -> Self {
+        Self {
+            prompts,
+            ..Default::default()
+        }
+    }
+
+    /// Registers a transfer. The frontend may pick the id itself, so it can
+    /// cancel the transfer before the command that runs it returns, and retry
+    /// it under the same id to resume it.
+    pub async fn begin(
+        &self,
+        transfer_id: Option<String>,
+        session_id: &str,
+        direction: TransferDirection,
+        source: &str,
+        destination: &str,
+        app_handle: tauri::AppHandle,
+    ) -> Result<Transfer> {
+        let resume_path = match &transfer_id {
+            Some(id) => self.interrupted.lock().await.remove(id).flatten(),
+            None => None,
+        };
+        let id = transfer_id.unwrap_or_else(|| {
+            format!("transfer-{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+        });
+
+        let mut active = self.active.lock().await;
+        if active.contains_key(&id) {
+            return Err(anyhow!("A transfer with id {} is already running", id));
+        }
+        let transfer = Transfer(Arc::new(TransferInner {
+            id: id.clone(),
+            session_id: session_id.to_string(),
+            direction,
+            source: source.to_string(),
+            destination: destination.to_string(),
+            app_handle,
+            prompts: self.prompts.clone(),
+            started: Instant::now(),
+            cancelled: AtomicBool::new(false),
+            keep_partial: AtomicBool::new(false),
+            resume: std::sync::Mutex::new(None),
+            in_flight: std::sync::Mutex::new(resume_path.clone()),
+            resume_path: std::sync::Mutex::new(resume_path),
+            verify_resume: AtomicBool::new(false),
+            conflict: std::sync::Mutex::new(ConflictPolicy::default()),
+            bytes_done: AtomicU64::new(0),
+            resumed_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(UNKNOWN_TOTAL),
+            last_emit_ms: AtomicU64::new(0),
+        }));
+        active.insert(id, transfer.clone());
+        transfer.emit(TransferState::Running, None);
+        Ok(transfer)
+    }
+
+    /// Unregisters the transfer and emits its final progress event.
+    pub async fn end(&self, transfer: &Transfer, result: &Result<()>) {
+        self.active.lock().await.remove(transfer.id());
+        if result.is_err() {
+            self.interrupted
+                .lock()
+                .await
+                .insert(transfer.id().to_string(), transfer.in_flight());
+        }
+        transfer.finish(result);
+    }
+
+    /// Stops a transfer at its next chunk. Unless `keep_partial` is set, the
+    /// partially written file is removed.
+    pub async fn cancel(&self, transfer_id: &str, keep_partial: bool) -> Result<()> {
+        let active = self.active.lock().await;
+        let transfer = active
+            .get(transfer_id)
+            .ok_or_else(|| anyhow!("No running transfer with id {}", transfer_id))?;
+        transfer
+            .0
+            .keep_partial
+            .store(keep_partial, Ordering::Relaxed);
+        transfer.0.cancelled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_name_numbers_before_the_extension() {
+        let taken = [
+            PathBuf::from("/d/report.txt"),
+            PathBuf::from("/d/report (1).txt"),
+        ];
+        let exists = |path: &Path| taken.iter().any(|taken| taken == path);
+        assert_eq!(
+            free_name(Path::new("/d/report.txt"), exists),
+            Path::new("/d/report (2).txt")
+        );
+        assert_eq!(
+            free_name(Path::new("/d/Makefile"), exists),
+            Path::new("/d/Makefile (1)")
+        );
+        assert_eq!(
+            free_name(Path::new("/d/archive.tar.gz"), exists),
+            Path::new("/d/archive.tar (1).gz")
+        );
+    }
+}
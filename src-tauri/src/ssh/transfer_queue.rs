@@ -0,0 +1,612 @@
+//! A queue of uploads and downloads that runs a limited number at a time,
+//! retries failures and is saved to disk, so unfinished jobs continue after a
+//! restart once their host is connected again.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{Emitter, Manager};
+use tokio::sync::{Mutex, Notify};
+
+use super::connection::SshConnection;
+use super::keygen::write_private_file;
+use super::sftp::{self, TreeOptions};
+use super::transfer::{Transfer, TransferDirection, TransferManager};
+
+const QUEUE_FILE: &str = "transfer-queue.json";
+
+/// How often waiting jobs are reconsidered, e.g. for a newly opened connection.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Delay before the first retry; each further attempt waits this much longer.
+const RETRY_DELAY_MS: i64 = 5_000;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobKind {
+    Download,
+    Upload,
+    DownloadDirectory,
+    UploadDirectory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    Queued,
+    Running,
+    /// Paused while running; becomes `Paused` once the run has wound down.
+    Stopping,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// A job as submitted by the frontend.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewJob {
+    pub kind: JobKind,
+    pub remote_path: String,
+    pub local_path: String,
+    #[serde(default)]
+    pub options: TreeOptions,
+    pub max_retries: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedJob {
+    /// Also the transfer id of its `sftp-progress` events.
+    pub id: String,
+    pub kind: JobKind,
+    /// `user@host:port`. Jobs run on any open connection to this host.
+    pub host: String,
+    pub remote_path: String,
+    pub local_path: String,
+    pub options: TreeOptions,
+    pub state: JobState,
+    pub attempts: u32,
+    pub max_retries: u32,
+    /// Unix milliseconds before which a failed job isn't retried.
+    pub retry_at: Option<i64>,
+    /// The file a stopped run was writing; the next run continues it.
+    #[serde(default)]
+    pub partial: Option<String>,
+    pub error: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QueueLimits {
+    /// Jobs running at once across all hosts.
+    pub global: usize,
+    /// Jobs running at once on one host. They share the connection's SFTP
+    /// channel, which serves one request at a time.
+    pub per_host: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            global: 4,
+            per_host: 1,
+        }
+    }
+}
+
+/// Payload of the `transfer-queue` event, and what is saved to disk.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub jobs: Vec<QueuedJob>,
+    #[serde(default)]
+    pub limits: QueueLimits,
+}
+
+#[derive(Default)]
+struct QueueInner {
+    jobs: Vec<QueuedJob>,
+    limits: QueueLimits,
+    app_handle: Option<tauri::AppHandle>,
+    /// The saved queue couldn't be read or moved aside, so it isn't replaced.
+    keep_file: bool,
+}
+
+#[derive(Clone)]
+pub struct TransferQueue {
+    inner: Arc<Mutex<QueueInner>>,
+    connections: Arc<Mutex<HashMap<String, SshConnection>>>,
+    transfers: TransferManager,
+    wake: Arc<Notify>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl TransferQueue {
+    pub fn new(
+        connections: Arc<Mutex<HashMap<String, SshConnection>>>,
+        transfers: TransferManager,
+    ) -> Self {
+        Self {
+            inner: Arc::default(),
+            connections,
+            transfers,
+            wake: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    /// Loads the saved queue and starts running jobs. Jobs that were running
+    /// when the app quit are queued again and pick up where they stopped. If
+    /// the saved queue can't be read, it is moved aside, the queue starts
+    /// empty and the error is returned.
+    pub async fn start(&self, app_handle: tauri::AppHandle) -> Result<()> {
+        let read = read_queue(&app_handle);
+        let saved = read.as_ref().cloned().unwrap_or_default();
+        {
+            let mut inner = self.inner.lock().await;
+            inner.jobs = saved.jobs;
+            inner.limits = saved.limits;
+            for job in &mut inner.jobs {
+                match job.state {
+                    JobState::Running => job.state = JobState::Queued,
+                    JobState::Stopping => job.state = JobState::Paused,
+                    _ => {}
+                }
+            }
+            inner.keep_file =
+                read.is_err() && queue_path(&app_handle).is_ok_and(|path| path.exists());
+            inner.app_handle = Some(app_handle);
+        }
+
+        let queue = self.clone();
+        tokio::spawn(async move {
+            loop {
+                queue.schedule().await;
+                tokio::select! {
+                    _ = queue.wake.notified() => {}
+                    _ = tokio::time::sleep(SCHEDULE_INTERVAL) => {}
+                }
+            }
+        });
+        read.map(|_| ())
+    }
+
+    pub async fn snapshot(&self) -> QueueSnapshot {
+        let inner = self.inner.lock().await;
+        QueueSnapshot {
+            jobs: inner.jobs.clone(),
+            limits: inner.limits,
+        }
+    }
+
+    /// Queues a job for the host `session_id` is connected to.
+    pub async fn add(&self, session_id: &str, job: NewJob) -> Result<QueuedJob> {
+        let connection = self
+            .connections
+            .lock()
+            .await
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Connection not found"))?;
+        let host = host_key(&connection).await;
+
+        let now = chrono::Utc::now();
+        let job = QueuedJob {
+            id: format!(
+                "job-{}-{}",
+                now.timestamp_millis(),
+                self.next_id.fetch_add(1, Ordering::Relaxed)
+            ),
+            kind: job.kind,
+            host,
+            remote_path: job.remote_path,
+            local_path: job.local_path,
+            options: job.options,
+            state: JobState::Queued,
+            attempts: 0,
+            max_retries: job.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            retry_at: None,
+            partial: None,
+            error: None,
+            created_at: now.timestamp(),
+        };
+        self.update(|inner| {
+            inner.jobs.push(job.clone());
+            Ok(())
+        })
+        .await?;
+        Ok(job)
+    }
+
+    /// Pauses one job, or every unfinished job when `job_id` is `None`.
+    /// Running jobs stop and keep their partial files; they stay `Stopping`
+    /// until their run has ended and can be resumed after that.
+    pub async fn pause(&self, job_id: Option<&str>) -> Result<()> {
+        let mut stopped = Vec::new();
+        self.update(|inner| {
+            for job in jobs_matching(&mut inner.jobs, job_id)? {
+                match job.state {
+                    JobState::Queued => job.state = JobState::Paused,
+                    JobState::Running => {
+                        job.state = JobState::Stopping;
+                        stopped.push(job.id.clone());
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        // A job that hasn't begun its transfer yet stops itself in `run`
+        for id in stopped {
+            let _ = self.transfers.cancel(&id, true).await;
+        }
+        Ok(())
+    }
+
+    /// Queues paused, failed or cancelled jobs again; `None` means all of them.
+    pub async fn resume(&self, job_id: Option<&str>) -> Result<()> {
+        self.update(|inner| {
+            for job in jobs_matching(&mut inner.jobs, job_id)? {
+                if matches!(
+                    job.state,
+                    JobState::Paused | JobState::Failed | JobState::Cancelled
+                ) {
+                    job.state = JobState::Queued;
+                    job.attempts = 0;
+                    job.retry_at = None;
+                    job.error = None;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Moves a job to `position` in the queue; earlier jobs start first.
+    pub async fn reorder(&self, job_id: &str, position: usize) -> Result<()> {
+        self.update(|inner| {
+            let index = job_index(&inner.jobs, job_id)?;
+            let job = inner.jobs.remove(index);
+            let position = position.min(inner.jobs.len());
+            inner.jobs.insert(position, job);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Drops a job from the queue, stopping it (and removing its partial
+    /// file) if it is running.
+    pub async fn remove(&self, job_id: &str) -> Result<()> {
+        let mut running = false;
+        self.update(|inner| {
+            let index = job_index(&inner.jobs, job_id)?;
+            running = matches!(
+                inner.jobs[index].state,
+                JobState::Running | JobState::Stopping
+            );
+            inner.jobs.remove(index);
+            Ok(())
+        })
+        .await?;
+        if running {
+            let _ = self.transfers.cancel(job_id, false).await;
+        }
+        Ok(())
+    }
+
+    pub async fn clear_finished(&self) -> Result<()> {
+        self.update(|inner| {
+            inner.jobs.retain(|job| job.state != JobState::Completed);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_limits(&self, limits: QueueLimits) -> Result<()> {
+        if limits.global == 0 || limits.per_host == 0 {
+            return Err(anyhow!("Limits must be at least 1"));
+        }
+        self.update(|inner| {
+            inner.limits = limits;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Applies `change`, then saves, notifies the frontend and reschedules.
+    async fn update(&self, change: impl FnOnce(&mut QueueInner) -> Result<()>) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        change(&mut inner)?;
+        publish(&inner)?;
+        drop(inner);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    async fn state(&self, job_id: &str) -> Option<JobState> {
+        let inner = self.inner.lock().await;
+        let index = job_index(&inner.jobs, job_id).ok()?;
+        Some(inner.jobs[index].state)
+    }
+
+    /// Saves the file a running job is writing, if it changed.
+    async fn record_partial(&self, job_id: &str, path: Option<PathBuf>) {
+        let path = path.map(|path| path.to_string_lossy().into_owned());
+        let mut inner = self.inner.lock().await;
+        let Ok(index) = job_index(&inner.jobs, job_id) else {
+            return;
+        };
+        if inner.jobs[index].partial != path {
+            inner.jobs[index].partial = path;
+            let _ = publish(&inner);
+        }
+    }
+
+    /// Starts queued jobs as far as the limits and open connections allow.
+    async fn schedule(&self) {
+        // Snapshot the connections first, so the queue lock is never held
+        // while waiting for the connections lock
+        let mut hosts: HashMap<String, (String, SshConnection)> = HashMap::new();
+        let connections: Vec<_> = self
+            .connections
+            .lock()
+            .await
+            .iter()
+            .map(|(id, connection)| (id.clone(), connection.clone()))
+            .collect();
+        for (session_id, connection) in connections {
+            let host = host_key(&connection).await;
+            hosts.entry(host).or_insert((session_id, connection));
+        }
+
+        let mut inner = self.inner.lock().await;
+        let Some(app_handle) = inner.app_handle.clone() else {
+            return;
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        let limits = inner.limits;
+        let mut running = 0;
+        let mut running_per_host: HashMap<String, usize> = HashMap::new();
+        for job in inner
+            .jobs
+            .iter()
+            .filter(|job| matches!(job.state, JobState::Running | JobState::Stopping))
+        {
+            running += 1;
+            *running_per_host.entry(job.host.clone()).or_default() += 1;
+        }
+
+        let mut to_start = Vec::new();
+        for job in &mut inner.jobs {
+            if running >= limits.global {
+                break;
+            }
+            let waiting = job.state == JobState::Queued && job.retry_at.is_none_or(|at| at <= now);
+            let host_running = running_per_host.entry(job.host.clone()).or_default();
+            if !waiting || *host_running >= limits.per_host {
+                continue;
+            }
+            let Some((session_id, connection)) = hosts.get(&job.host) else {
+                continue;
+            };
+
+            job.state = JobState::Running;
+            job.retry_at = None;
+            running += 1;
+            *host_running += 1;
+            to_start.push((job.clone(), session_id.clone(), connection.clone()));
+        }
+        if to_start.is_empty() {
+            return;
+        }
+        let _ = publish(&inner);
+        drop(inner);
+
+        for (job, session_id, connection) in to_start {
+            let queue = self.clone();
+            let app_handle = app_handle.clone();
+            tokio::spawn(async move { queue.run(job, session_id, connection, app_handle).await });
+        }
+    }
+
+    async fn run(
+        &self,
+        job: QueuedJob,
+        session_id: String,
+        connection: SshConnection,
+        app_handle: tauri::AppHandle,
+    ) {
+        let (direction, source, destination) = match job.kind {
+            JobKind::Download | JobKind::DownloadDirectory => (
+                TransferDirection::Download,
+                &job.remote_path,
+                &job.local_path,
+            ),
+            JobKind::Upload | JobKind::UploadDirectory => {
+                (TransferDirection::Upload, &job.local_path, &job.remote_path)
+            }
+        };
+
+        let mut partial = None;
+        let result = match self
+            .transfers
+            .begin(
+                Some(job.id.clone()),
+                &session_id,
+                direction,
+                source,
+                destination,
+                app_handle,
+            )
+            .await
+        {
+            Ok(transfer) => {
+                // Paused or removed after it was scheduled but before the
+                // transfer existed, so `pause`/`remove` couldn't cancel it
+                match self.state(&job.id).await {
+                    Some(JobState::Running) => {}
+                    state => {
+                        let keep_partial = state.is_some();
+                        let _ = self.transfers.cancel(&job.id, keep_partial).await;
+                    }
+                }
+                transfer.set_options(&job.options.transfer);
+                transfer.set_resume_path(job.partial.as_ref().map(PathBuf::from));
+
+                // Keep the saved partial file current, so a run cut short by
+                // quitting the app is continued after the restart
+                let copy = copy(&job, &connection, &transfer);
+                tokio::pin!(copy);
+                let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+                let result = loop {
+                    tokio::select! {
+                        result = &mut copy => break result,
+                        _ = interval.tick() => {
+                            self.record_partial(&job.id, transfer.in_flight()).await;
+                        }
+                    }
+                };
+                self.transfers.end(&transfer, &result).await;
+                partial = transfer.in_flight();
+                result
+            }
+            Err(e) => Err(e),
+        };
+
+        let _ = self
+            .update(|inner| {
+                // Removed while it ran
+                let Ok(index) = job_index(&inner.jobs, &job.id) else {
+                    return Ok(());
+                };
+                let job = &mut inner.jobs[index];
+                job.partial = partial.map(|path| path.to_string_lossy().into_owned());
+                match result {
+                    // Paused jobs were stopped on purpose and stay paused, even
+                    // if the copy finished before the pause reached it
+                    _ if job.state == JobState::Stopping => job.state = JobState::Paused,
+                    Ok(()) => {
+                        job.state = JobState::Completed;
+                        job.error = None;
+                    }
+                    Err(e) if e.is::<super::transfer::TransferCancelled>() => {
+                        job.state = JobState::Cancelled;
+                    }
+                    Err(e) => {
+                        job.attempts += 1;
+                        job.error = Some(e.to_string());
+                        if job.attempts <= job.max_retries {
+                            job.state = JobState::Queued;
+                            job.retry_at = Some(
+                                chrono::Utc::now().timestamp_millis()
+                                    + RETRY_DELAY_MS * job.attempts as i64,
+                            );
+                        } else {
+                            job.state = JobState::Failed;
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .await;
+    }
+}
+
+async fn copy(job: &QueuedJob, connection: &SshConnection, transfer: &Transfer) -> Result<()> {
+    let (remote, local) = (&job.remote_path, &job.local_path);
+    match job.kind {
+        JobKind::Download => sftp::download_file(connection, remote, local, transfer).await,
+        JobKind::Upload => sftp::upload_file(connection, local, remote, transfer).await,
+        JobKind::DownloadDirectory => {
+            let options = job.options.clone();
+            sftp::download_directory(connection, remote, local, options, transfer).await
+        }
+        JobKind::UploadDirectory => {
+            let options = job.options.clone();
+            sftp::upload_directory(connection, local, remote, options, transfer).await
+        }
+    }
+}
+
+async fn host_key(connection: &SshConnection) -> String {
+    let metadata = connection.metadata().await;
+    format!("{}@{}:{}", metadata.username, metadata.host, metadata.port)
+}
+
+fn job_index(jobs: &[QueuedJob], job_id: &str) -> Result<usize> {
+    jobs.iter()
+        .position(|job| job.id == job_id)
+        .ok_or_else(|| anyhow!("No queued transfer with id {}", job_id))
+}
+
+fn jobs_matching<'a>(
+    jobs: &'a mut [QueuedJob],
+    job_id: Option<&str>,
+) -> Result<Vec<&'a mut QueuedJob>> {
+    match job_id {
+        Some(id) => {
+            let index = job_index(jobs, id)?;
+            Ok(vec![&mut jobs[index]])
+        }
+        None => Ok(jobs.iter_mut().collect()),
+    }
+}
+
+/// Saves the queue and sends it to the frontend.
+fn publish(inner: &QueueInner) -> Result<()> {
+    let Some(ref app_handle) = inner.app_handle else {
+        return Ok(());
+    };
+    let snapshot = QueueSnapshot {
+        jobs: inner.jobs.clone(),
+        limits: inner.limits,
+    };
+    if !inner.keep_file {
+        let path = queue_path(app_handle)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Written aside and renamed, so a crash mid-write can't truncate the queue
+        write_private_file(&path, serde_json::to_string_pretty(&snapshot)?.as_bytes())?;
+    }
+    let _ = app_handle.emit("transfer-queue", snapshot);
+    Ok(())
+}
+
+fn queue_path(app_handle: &tauri::AppHandle) -> Result<PathBuf> {
+    Ok(app_handle.path().app_data_dir()?.join(QUEUE_FILE))
+}
+
+fn read_queue(app_handle: &tauri::AppHandle) -> Result<QueueSnapshot> {
+    let path = queue_path(app_handle)?;
+    if !path.exists() {
+        return Ok(QueueSnapshot::default());
+    }
+    let parsed = std::fs::read_to_string(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| serde_json::from_str(&contents).map_err(Into::into));
+    parsed.map_err(|e| {
+        // Kept for inspection instead of being replaced by the next save
+        let aside = path.with_extension(format!("json.bad-{}", chrono::Utc::now().timestamp()));
+        match std::fs::rename(&path, &aside) {
+            Ok(()) => anyhow!(
+                "Failed to read {}: {}; moved it to {}",
+                path.display(),
+                e,
+                aside.display()
+            ),
+            Err(move_error) => anyhow!(
+                "Failed to read {}: {}; could not move it aside: {}",
+                path.display(),
+                e,
+                move_error
+            ),
+        }
+    })
+}